@@ -0,0 +1,329 @@
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::fs::create_dir_all;
+use std::str::FromStr;
+use rustfft::num_complex::Complex32;
+use crate::utils;
+use crate::config::ProjectSettings;
+use crate::cfl;
+use crate::fft::{fft_axes,readout_slab,to_complex,to_interleaved};
+use crate::cs_solver::{sampled_lines,residual_norm,wavelet_l1};
+use crate::bart_wrapper::{BartPicsSettings,bart_pics,copy_weights};
+
+/*
+Automatic choice of the pics regularization weight. A coarse set of lambdas is reconstructed on the central
+readout positions of a volume, and for each the data consistency ||M F S x - y|| and the sparsity of x are
+measured. Either the corner of the L-curve (log residual vs log sparsity) or the discrepancy principle
+(the largest lambda whose residual stays at the noise level) picks the proposal
+*/
+#[derive(Debug,Deserialize,Serialize,Clone,Copy,PartialEq,Eq)]
+pub enum LambdaSelection{
+    LCurve,
+    Discrepancy,
+}
+
+impl FromStr for LambdaSelection{
+    type Err = String;
+    fn from_str(s:&str) -> Result<Self,Self::Err>{
+        return match s.to_ascii_lowercase().as_str(){
+            "l-curve" | "lcurve" => Ok(LambdaSelection::LCurve),
+            "discrepancy" => Ok(LambdaSelection::Discrepancy),
+            _ => Err(format!("unknown lambda selection {}. Use l-curve or discrepancy",s))
+        }
+    }
+}
+
+#[derive(Debug,Clone)]
+pub struct AutoLambdaSettings{
+    pub lambdas:Vec<f32>,
+    /* central readout positions reconstructed for each lambda */
+    pub crop:usize,
+    pub max_iter:u32,
+    pub selection:LambdaSelection,
+    /* fraction of the readout at each end used to estimate the noise */
+    pub noise_fraction:f64,
+}
+
+impl Default for AutoLambdaSettings{
+    fn default() -> AutoLambdaSettings{
+        return AutoLambdaSettings{
+            lambdas:vec![0.0001,0.0003,0.001,0.003,0.01,0.03,0.1],
+            crop:32,
+            max_iter:30,
+            selection:LambdaSelection::LCurve,
+            noise_fraction:1.0/16.0,
+        }
+    }
+}
+
+#[derive(Debug,Clone,PartialEq,Serialize,Deserialize)]
+pub struct CurvePoint{
+    pub lambda:f32,
+    pub residual:f64,
+    pub sparsity:f64,
+}
+
+#[derive(Debug,Clone,Serialize,Deserialize)]
+pub struct LambdaProposal{
+    pub selection:LambdaSelection,
+    pub algorithm:String,
+    pub noise_sigma:f64,
+    pub measured_samples:usize,
+    /* residual expected from noise alone, the discrepancy principle target */
+    pub noise_residual:f64,
+    pub lambda:f32,
+    /* kept last because toml tables must follow plain values */
+    pub curve:Vec<CurvePoint>,
+}
+
+/* the proposal as a [lambda_proposal] table after the project settings */
+#[derive(Serialize)]
+struct ProposalSection<'a>{
+    lambda_proposal:&'a LambdaProposal,
+}
+
+impl LambdaProposal{
+    pub fn to_file(&self,dest_path:&str){
+        let s = serde_json::to_string_pretty(&self).expect("trouble serializing data struct");
+        utils::write_to_file(dest_path,"json",&s);
+    }
+
+    /*
+    writes the project settings carrying the proposed lambda, followed by the selection and the curve it
+    came from. The file still opens as project settings
+    */
+    pub fn to_project_file(&self,project:&ProjectSettings,dest_path:&str){
+        let settings = toml::to_string(project).expect("cannot serialize struct");
+        let section = toml::to_string(&ProposalSection{lambda_proposal:self}).expect("trouble serializing data struct");
+        utils::write_to_file(dest_path,"toml",&format!("{}\n{}",settings,section));
+    }
+}
+
+/*
+noise_sigma
+rms of the sampled k-space at both ends of the readout, where there is essentially no signal.
+The expected squared residual of n measured samples is n*sigma^2
+*/
+pub fn noise_sigma(kspace:&[Vec<Complex32>],readout:usize,fraction:f64) -> f64{
+    let edge = ((readout as f64*fraction).ceil() as usize).max(1);
+    let mask = sampled_lines(kspace,readout);
+    let mut sum = 0.0;
+    let mut n = 0;
+    for coil in kspace.iter(){
+        for (line,_) in mask.iter().enumerate().filter(|(_,m)| **m){
+            let samples = &coil[line*readout..(line+1)*readout];
+            samples[..edge].iter().chain(samples[readout-edge..].iter()).for_each(|v|{
+                sum += v.norm_sqr() as f64;
+                n += 1;
+            });
+        }
+    }
+    if n == 0 {panic!("no sampled k-space to estimate the noise from")}
+    return (sum/n as f64).sqrt();
+}
+
+/* index of the point of highest curvature on the log-log L-curve. The curve must be sorted by lambda */
+pub fn l_curve_corner(curve:&[CurvePoint]) -> usize{
+    if curve.len() < 3 {panic!("at least 3 lambdas are needed to find an L-curve corner")}
+    let p:Vec<(f64,f64)> = curve.iter().map(|c| (c.residual.max(f64::MIN_POSITIVE).ln(),c.sparsity.max(f64::MIN_POSITIVE).ln())).collect();
+    let dist = |a:(f64,f64),b:(f64,f64)| ((a.0-b.0).powi(2) + (a.1-b.1).powi(2)).sqrt();
+    // menger curvature of each interior point with its neighbors
+    let curvature = |i:usize| -> f64{
+        let (a,b,c) = (p[i-1],p[i],p[i+1]);
+        let cross = (b.0-a.0)*(c.1-a.1) - (b.1-a.1)*(c.0-a.0);
+        let denom = dist(a,b)*dist(b,c)*dist(a,c);
+        let k = 2.0*cross.abs()/denom;
+        return if denom > 0.0 && k.is_finite() {k} else {0.0};
+    };
+    return (1..curve.len()-1).max_by(|a,b| curvature(*a).total_cmp(&curvature(*b))).unwrap();
+}
+
+/* drops points where pics diverged (nan or infinite residual or sparsity) so they can't be selected */
+pub fn finite_points(curve:Vec<CurvePoint>) -> Vec<CurvePoint>{
+    return curve.into_iter().filter(|c|{
+        let finite = c.residual.is_finite() && c.sparsity.is_finite();
+        if !finite {println!("warning: lambda {} gave residual {} and sparsity {}. It is left out of the selection",c.lambda,c.residual,c.sparsity)}
+        return finite;
+    }).collect();
+}
+
+/*
+discrepancy_lambda
+the lambda where the residual reaches the noise level, interpolated in log lambda between the
+bracketing points. The curve must be sorted by lambda
+*/
+pub fn discrepancy_lambda(curve:&[CurvePoint],noise_residual:f64) -> f32{
+    let above = match curve.iter().position(|c| c.residual >= noise_residual){
+        Some(i) => i,
+        None => return curve.last().expect("empty lambda curve").lambda
+    };
+    if above == 0 {return curve[0].lambda}
+    let (lo,hi) = (&curve[above-1],&curve[above]);
+    let t = (noise_residual - lo.residual)/(hi.residual - lo.residual);
+    let log_lambda = (lo.lambda as f64).ln() + t*((hi.lambda as f64).ln() - (lo.lambda as f64).ln());
+    return log_lambda.exp() as f32;
+}
+
+/*
+write_cropped
+keeps the central crop readout positions of the k-space and writes them to cropped_cfl, with the
+sample weights of kspace_cfl when it has them
+*/
+pub fn write_cropped(raw:Vec<Complex32>,dims:&[usize],crop:usize,kspace_cfl:&str,cropped_cfl:&Path) -> Vec<Complex32>{
+    let mut hybrid = raw;
+    fft_axes(&mut hybrid,dims,&[0],true);
+    let cropped = readout_slab(&hybrid,dims,(dims[0] - crop)/2,crop);
+    let mut cropped_dims = dims.to_vec();
+    cropped_dims[0] = crop;
+    cfl::write(cropped_cfl,&to_interleaved(&cropped),&cropped_dims);
+    copy_weights(kspace_cfl,cropped_cfl.to_str().unwrap());
+    return cropped;
+}
+
+/*
+propose_lambda
+runs pics for each lambda on the cropped k-space under outdir and returns the curve with the chosen lambda.
+The curve is also written to outdir/lambda_curve.json
+*/
+pub fn propose_lambda(kspace_cfl:&str,outdir:&Path,base:&BartPicsSettings,settings:&AutoLambdaSettings) -> LambdaProposal{
+    create_dir_all(outdir).expect("cannot make directory");
+    let mut dims = cfl::get_all_dims(Path::new(kspace_cfl));
+    while dims.len() < 4 {dims.push(1)}
+    let readout = dims[0];
+    let n = dims[0]*dims[1]*dims[2];
+    let raw = to_complex(&cfl::load(Path::new(kspace_cfl)));
+    let kspace:Vec<Vec<Complex32>> = raw.chunks_exact(n).map(|c| c.to_vec()).collect();
+    let sigma = noise_sigma(&kspace,readout,settings.noise_fraction);
+    println!("noise estimate from the outer k-space: {}",sigma);
+
+    let crop = settings.crop.min(readout);
+    let spatial = [crop,dims[1],dims[2]];
+    let cropped_cfl = outdir.join("kspace_cropped");
+    let cropped = write_cropped(raw,&dims,crop,kspace_cfl,&cropped_cfl);
+    let cropped:Vec<Vec<Complex32>> = cropped.chunks_exact(crop*dims[1]*dims[2]).map(|c| c.to_vec()).collect();
+    let measured = sampled_lines(&cropped,crop).iter().filter(|m| **m).count()*crop*cropped.len();
+
+    let algorithm = base.algorithm().to_string();
+    let mut lambdas:Vec<f32> = settings.lambdas.iter().cloned().filter(|l|{
+        let usable = l.is_finite() && *l > 0.0;
+        if !usable {println!("warning: ignoring lambda {}",l)}
+        return usable;
+    }).collect();
+    lambdas.sort_by(|a,b| a.total_cmp(b));
+    let shared_sens = outdir.join("coil_sens");
+    let curve:Vec<CurvePoint> = lambdas.iter().map(|lambda|{
+        let dir = outdir.join(format!("lambda{}",lambda));
+        create_dir_all(&dir).expect("cannot make directory");
+        let img = dir.join("imspace");
+        let mut s = base.clone();
+        s.regularizers.clear();
+        // the residual is only meaningful with the image on the scale of the data
+        s.set_regularization(&algorithm,*lambda).set_max_iter(settings.max_iter).set_respect_scaling(true);
+        bart_pics(cropped_cfl.to_str().unwrap(),img.to_str().unwrap(),shared_sens.to_str().unwrap(),&dir.join("bart.log"),&mut s);
+
+        let x = to_complex(&cfl::load(&img));
+        let sens = to_complex(&cfl::load(Path::new(s.coil_sensitivity())));
+        let sens:Vec<Vec<Complex32>> = sens.chunks_exact(x.len()).map(|c| c.to_vec()).collect();
+        let residual = residual_norm(&cropped,Some(sens),spatial,&x).sqrt();
+        let sparsity = match algorithm.as_str(){
+            "l2" => x.iter().map(|v| v.norm_sqr() as f64).sum::<f64>().sqrt(),
+            _ => wavelet_l1(&x,spatial,3),
+        };
+        println!("lambda {}: residual {} sparsity {}",lambda,residual,sparsity);
+        CurvePoint{lambda:*lambda,residual:residual,sparsity:sparsity}
+    }).collect();
+
+    let noise_residual = sigma*(measured as f64).sqrt();
+    let candidates = finite_points(curve.clone());
+    let lambda = match settings.selection{
+        LambdaSelection::LCurve => candidates[l_curve_corner(&candidates)].lambda,
+        LambdaSelection::Discrepancy => discrepancy_lambda(&candidates,noise_residual),
+    };
+    let proposal = LambdaProposal{
+        selection:settings.selection,
+        algorithm:algorithm,
+        noise_sigma:sigma,
+        measured_samples:measured,
+        noise_residual:noise_residual,
+        curve:curve,
+        lambda:lambda,
+    };
+    proposal.to_file(outdir.join("lambda_curve").to_str().unwrap());
+    return proposal;
+}
+
+#[test]
+fn test_lambda_selection(){
+    // residual grows with lambda while sparsity falls off, with a sharp bend at 0.003
+    let curve:Vec<CurvePoint> = [(0.0001,1.0,100.0),(0.0003,1.05,60.0),(0.001,1.1,30.0),(0.003,1.2,10.0),(0.01,5.0,9.0),(0.03,20.0,8.5),(0.1,80.0,8.0)]
+        .iter().map(|(l,r,s)| CurvePoint{lambda:*l,residual:*r,sparsity:*s}).collect();
+    assert_eq!(l_curve_corner(&curve),3);
+    assert_eq!(discrepancy_lambda(&curve,0.5),0.0001);
+    assert_eq!(discrepancy_lambda(&curve,100.0),0.1);
+    let l = discrepancy_lambda(&curve,3.1);
+    assert!(l > 0.003 && l < 0.01);
+    assert_eq!("l-curve".parse::<LambdaSelection>(),Ok(LambdaSelection::LCurve));
+    // a diverged reconstruction is dropped instead of panicking the selection
+    let mut diverged = curve.clone();
+    diverged[5].residual = f64::NAN;
+    assert_eq!(l_curve_corner(&diverged),3);
+    let diverged = finite_points(diverged);
+    assert_eq!(diverged.len(),curve.len() - 1);
+    assert_eq!(l_curve_corner(&diverged),3);
+
+    // noise of rms 2 at the ends of the readout, signal in the middle. The second line isn't sampled
+    let readout = 16;
+    let mut coil = vec![Complex32::new(0.0,0.0);2*readout];
+    coil[..readout].iter_mut().enumerate().for_each(|(i,v)|{
+        *v = if (2..14).contains(&i) {Complex32::new(50.0,0.0)} else if i % 2 == 0 {Complex32::new(2.0,0.0)} else {Complex32::new(0.0,-2.0)};
+    });
+    assert!((noise_sigma(&[coil],readout,0.125) - 2.0).abs() < 1e-9);
+
+    // weighted pics on the crop needs the weights next to the cropped k-space
+    use crate::bart_wrapper::weights_cfl;
+    let dir = std::env::temp_dir();
+    let kspace = dir.join("cs_reco_test_lambda_kspace");
+    let cropped_cfl = dir.join("cs_reco_test_lambda_kspace_cropped");
+    let dims = [8,2,2,1];
+    cfl::write(Path::new(&weights_cfl(kspace.to_str().unwrap())),&vec![1.0;2*4],&[1,2,2]);
+    let cropped = write_cropped(vec![Complex32::new(1.0,0.0);32],&dims,4,kspace.to_str().unwrap(),&cropped_cfl);
+    assert_eq!(cropped.len(),16);
+    assert_eq!(cfl::get_all_dims(&cropped_cfl),vec![4,2,2,1,1]);
+    let mut s = BartPicsSettings::default();
+    s.sample_weights = true;
+    let cropped_weights = weights_cfl(cropped_cfl.to_str().unwrap());
+    assert!(Path::new(&cropped_weights).with_extension("cfl").exists());
+    assert!(s.pics_command(cropped_cfl.to_str().unwrap(),"sens","img").argv().contains(&cropped_weights));
+
+    // the proposal file opens as project settings and keeps the curve it was chosen from
+    let proposal = LambdaProposal{
+        selection:LambdaSelection::LCurve,
+        algorithm:"l1".to_string(),
+        noise_sigma:2.0,
+        measured_samples:64,
+        noise_residual:16.0,
+        lambda:curve[3].lambda,
+        curve:curve.clone(),
+    };
+    let project = ProjectSettings{
+        label:"lambda_test".to_string(),
+        project_code:"00.test.00".to_string(),
+        incomplete_mrd:Default::default(),
+        zero_fill:Default::default(),
+        channels:None,
+        recon_settings:BartPicsSettings::default(),
+        engine:Default::default(),
+        slabs:None,
+        pe_table:Default::default(),
+    };
+    let path = dir.join("cs_reco_test_lambda_proposal");
+    proposal.to_project_file(&project,path.to_str().unwrap());
+    let s = utils::read_to_string(path.to_str().unwrap(),"toml").unwrap();
+    let reopened:ProjectSettings = toml::from_str(&s).unwrap();
+    assert_eq!(reopened.label,"lambda_test");
+    let value:toml::Value = toml::from_str(&s).unwrap();
+    let section = &value["lambda_proposal"];
+    assert_eq!(section["selection"].as_str(),Some("LCurve"));
+    assert_eq!(section["curve"].as_array().unwrap().len(),curve.len());
+    assert_eq!(section["curve"][3]["residual"].as_float(),Some(1.2));
+}
@@ -1,14 +1,85 @@
 use serde::{Deserialize, Serialize};
 use std::io::{Write, Read};
 use std::path::{Path,PathBuf};
-use std::fs::File;
-use std::process::{Command, CommandArgs};
+use std::fs::{File,OpenOptions,remove_file};
+use std::process::{Command, CommandArgs, Stdio};
+use std::fmt;
+use std::time::Duration;
 use toml;
 use crate::utils::{self, vec_to_string};
 use crate::cfl;
 use crate::mrd::{mrd_to_cfl};
 
-#[derive(Debug,Deserialize,Serialize)]
+/*
+SensitivityMode
+how the coil sensitivity map handed to pics is made
+    Unit: all-ones map (bart ones)
+    Espirit: estimated from the fully sampled k-space center (bart ecalib)
+    File: an existing map given by coil_sensitivity
+*/
+#[derive(Debug,Deserialize,Serialize,Clone,Copy,PartialEq,Eq,Default)]
+pub enum SensitivityMode{
+    #[default]
+    Unit,
+    Espirit,
+    File,
+}
+
+/*
+Regularizer
+one "-R <kind>:<transform_flags>:<joint_flags>:<lambda>" term of pics. Flags are bart bitmasks
+over the data dimensions (7 = dims 0,1,2)
+*/
+#[derive(Debug,Deserialize,Serialize,Clone,PartialEq)]
+pub struct Regularizer{
+    pub kind:RegularizerKind,
+    pub transform_flags:u32,
+    pub joint_flags:u32,
+    pub lambda:f32,
+}
+
+#[derive(Debug,Deserialize,Serialize,Clone,Copy,PartialEq,Eq)]
+pub enum RegularizerKind{
+    Wavelet,
+    TotalVariation,
+    LocallyLowRank,
+    L1,
+    L2,
+}
+
+/* iterative algorithm pics runs. Default lets bart choose */
+#[derive(Debug,Deserialize,Serialize,Clone,Copy,PartialEq,Eq,Default)]
+pub enum PicsSolver{
+    #[default]
+    Default,
+    Ist,
+    Fista,
+    Admm,
+}
+
+impl RegularizerKind{
+    pub fn code(&self) -> &str{
+        return match self{
+            RegularizerKind::Wavelet => "W",
+            RegularizerKind::TotalVariation => "T",
+            RegularizerKind::LocallyLowRank => "L",
+            RegularizerKind::L1 => "I",
+            RegularizerKind::L2 => "Q",
+        }
+    }
+}
+
+impl Regularizer{
+    pub fn new(kind:RegularizerKind,transform_flags:u32,joint_flags:u32,lambda:f32) -> Regularizer{
+        return Regularizer{kind:kind,transform_flags:transform_flags,joint_flags:joint_flags,lambda:lambda};
+    }
+
+    pub fn arg(&self) -> String{
+        return format!("-R{}:{}:{}:{}",self.kind.code(),self.transform_flags,self.joint_flags,self.lambda);
+    }
+}
+
+#[derive(Debug,Deserialize,Serialize,Clone)]
 pub struct BartPicsSettings{
     bart_binary:String,
     max_iter:u32,
@@ -17,9 +88,39 @@ pub struct BartPicsSettings{
     regularization:f32,
     debug:bool,
     coil_sensitivity:String,
-    pub image_scale_histo_percent:f64
+    pub image_scale_histo_percent:f64,
+    #[serde(default)]
+    pub sensitivity_mode:SensitivityMode,
+    #[serde(default="default_calibration_size")]
+    pub calibration_size:u32,
+    #[serde(default="default_espirit_threshold")]
+    pub espirit_threshold:f32,
+    #[serde(default)]
+    pub solver:PicsSolver,
+    /* ADMM penalty (-u) */
+    #[serde(default)]
+    pub admm_rho:Option<f32>,
+    /* max conjugate gradient iterations inside ADMM (-C) */
+    #[serde(default)]
+    pub admm_max_cg_iter:Option<u32>,
+    /* iteration step size (-s) */
+    #[serde(default)]
+    pub step_size:Option<f32>,
+    /* block size for locally low-rank regularization (-b) */
+    #[serde(default)]
+    pub llr_block_size:Option<u32>,
+    /* weight data consistency by the <kspace>_weights pattern written with the zero-filled k-space (-p) */
+    #[serde(default)]
+    pub sample_weights:bool,
+    /* when empty, the single algorithm/regularization term is used instead.
+    Kept last because toml tables must follow plain values */
+    #[serde(default)]
+    pub regularizers:Vec<Regularizer>,
 }
 
+fn default_calibration_size() -> u32 {24}
+fn default_espirit_threshold() -> f32 {0.001}
+
 impl BartPicsSettings{
     pub fn default() -> BartPicsSettings{
         return BartPicsSettings{
@@ -30,7 +131,17 @@ impl BartPicsSettings{
             regularization:0.005,
             debug:true,
             coil_sensitivity:"".to_string(),
-            image_scale_histo_percent:0.9995
+            image_scale_histo_percent:0.9995,
+            sensitivity_mode:SensitivityMode::Unit,
+            calibration_size:default_calibration_size(),
+            espirit_threshold:default_espirit_threshold(),
+            solver:PicsSolver::Default,
+            admm_rho:None,
+            admm_max_cg_iter:None,
+            step_size:None,
+            llr_block_size:None,
+            sample_weights:false,
+            regularizers:Vec::new(),
         }
     }
 
@@ -55,95 +166,488 @@ impl BartPicsSettings{
         return toml::from_str(&s).expect("cannot deserialize file");
     }
 
-    pub fn cmd_stub(&self) -> String {
-        let reg = format!("-{}",self.regularization);
-        let algo = format!("-{}",self.algorithm);
-        let scale = if self.respect_scaling { "-S" } else { "" };
-        let debug = if self.debug {"-d5"} else {""};
-        let iter = format!("-i{}",self.max_iter);
-        return format!("{} pics {} {} {} {} {}",self.bart_binary,algo,reg,iter,scale,debug);
+    pub fn algorithm(&self) -> &str{
+        return &self.algorithm;
     }
 
-    pub fn cmd_full(&self,cfl_in:&str,cfl_out:&str){
-        let mut pin = Path::new(cfl_in).to_owned().with_extension("");
-        let mut pout = Path::new(cfl_out).to_owned().with_extension("");
-        // create kspace sensitivity data
-        let mut ksens = pin.clone();
-        let mut fname = ksens.file_name().expect("cannot extract file name from path").to_str().unwrap().to_string();
-        fname.push_str("_sens"); // in the future we will likely only need one of these, not one for every volume
-        ksens = ksens.with_file_name(fname);
+    pub fn coil_sensitivity(&self) -> &str{
+        return &self.coil_sensitivity;
     }
 
-    pub fn set_unit_coil_sens(&mut self,sens_cfl:&str,dims:Vec<usize>){
-        self.coil_sensitivity = sens_cfl.to_string();
-        println!("writing unit sens");
-        let mut cmd = Command::new(&self.bart_binary);
-        cmd.arg("ones")
-        .arg(dims.len().to_string());
-        for d in dims{
-            cmd.arg(d.to_string());
+    pub fn set_respect_scaling(&mut self,respect_scaling:bool) -> &mut Self{
+        self.respect_scaling = respect_scaling;
+        return self;
+    }
+
+    pub fn set_max_iter(&mut self,max_iter:u32) -> &mut Self{
+        self.max_iter = max_iter;
+        return self;
+    }
+
+    /* single regularization term, used when no stacked regularizers are given */
+    pub fn set_regularization(&mut self,algorithm:&str,lambda:f32) -> &mut Self{
+        self.algorithm = algorithm.to_string();
+        self.regularization = lambda;
+        return self;
+    }
+
+    pub fn add_regularizer(&mut self,regularizer:Regularizer) -> &mut Self{
+        self.regularizers.push(regularizer);
+        return self;
+    }
+
+    /* regularization terms as pics arguments */
+    pub fn regularization_args(&self) -> Vec<String>{
+        if self.regularizers.is_empty(){
+            return vec![format!("-{}",self.algorithm),format!("-r{}",self.regularization)];
         }
-        cmd.arg(sens_cfl);
-        println!("{:?}",cmd);
-        let proc = cmd.spawn().expect("failed to start bart ones");
-        let result = proc.wait_with_output().expect("failed to wait for output");
-        if !result.status.success(){
-            println!("command failed!");
+        return self.regularizers.iter().map(|r| r.arg()).collect();
+    }
+
+    /* solver selection and parameters as pics arguments */
+    pub fn solver_args(&self) -> Vec<String>{
+        let mut args = Vec::<String>::new();
+        match self.solver{
+            PicsSolver::Default => {},
+            PicsSolver::Ist => args.push("-I".to_string()),
+            PicsSolver::Fista => args.push("--fista".to_string()),
+            PicsSolver::Admm => args.push("-m".to_string()),
         }
+        if let Some(rho) = self.admm_rho {args.push(format!("-u{}",rho))}
+        if let Some(n) = self.admm_max_cg_iter {args.push(format!("-C{}",n))}
+        if let Some(step) = self.step_size {args.push(format!("-s{}",step))}
+        if let Some(b) = self.llr_block_size {args.push(format!("-b{}",b))}
+        return args;
+    }
+
+    /* the full pics command line for one reconstruction */
+    pub fn pics_command(&self,kspace_cfl:&str,sens_cfl:&str,img_cfl:&str) -> BartCommand{
+        let mut cmd = BartCommand::new(&self.bart_binary,"pics");
+        cmd.args(self.regularization_args())
+        .args(self.solver_args())
+        .arg(format!("-i{}",self.max_iter))
+        .flag_if(self.respect_scaling,"-S")
+        .flag_if(self.debug,"-d5");
+        if self.sample_weights {cmd.arg("-p").arg(weights_cfl(kspace_cfl));}
+        cmd.arg(kspace_cfl).arg(sens_cfl).arg(img_cfl);
+        return cmd;
+    }
+
+    /* all-ones map with the k-space dims, singletons included so both have the same layout */
+    pub fn unit_sens_command(&self,kspace_cfl:&str,sens_cfl:&str) -> BartCommand{
+        return BartCommand::ones(&self.bart_binary,&cfl::get_dims_trimmed(Path::new(kspace_cfl)),sens_cfl);
+    }
+
+    pub fn set_unit_coil_sens(&mut self,sens_cfl:&str,kspace_cfl:&str){
+        self.coil_sensitivity = sens_cfl.to_string();
+        println!("writing unit sens");
+        self.unit_sens_command(kspace_cfl,sens_cfl).run();
+    }
+
+    pub fn bart_binary(&self) -> &str{
+        return &self.bart_binary;
     }
 
     pub fn set_bart_binary(&mut self,binary_path:&str){
+        if let Err(e) = resolve_binary(binary_path){panic!("{}",e)}
         self.bart_binary = binary_path.to_string();
-        let p = Path::new(binary_path);
-        //if !p.exists(){panic!("bart binary not found at {}",binary_path)}
-    }
-
-    pub fn set_unit_sens_from_cfl(&mut self,cfl:&str){
-        let cfl_path = Path::new(cfl);
-        let p_in = Path::new(cfl).with_extension("").to_owned();
-        let mut coil_sens = p_in.clone();
-        let mut name = coil_sens.file_name().unwrap().to_str().unwrap().to_string();
-        name.push_str("_sens");
-        coil_sens = coil_sens.with_file_name(name);
-        let dims = cfl::get_dims(&cfl_path);
-        self.set_unit_coil_sens(coil_sens.to_str().unwrap(),dims);
-    }
-}
-
-pub fn bart_pics(kspace_cfl:&str,img_cfl:&str,settings:&mut BartPicsSettings){
-
-    let kspace_cfl_path = Path::new(&kspace_cfl);
-    
-    let sens_path = Path::new(&settings.coil_sensitivity);
-    // reference coil sensitivity data if exist and is correct, make fresh if otherwise
-    if settings.coil_sensitivity.is_empty(){
-        settings.set_unit_sens_from_cfl(kspace_cfl);
-    }else {
-        let sens_dims = cfl::get_dims(sens_path);
-        let kspace_dims = cfl::get_dims(kspace_cfl_path);
-        if sens_dims != kspace_dims{
-            settings.set_unit_sens_from_cfl(kspace_cfl);
+    }
+
+    /*
+    probe_bart
+    checks that the bart binary can be run, asks it for its version and rejects settings that are known
+    to need a newer bart (see check_capabilities). Call this before submitting any jobs
+    */
+    pub fn probe_bart(&self) -> Result<BartVersion,String>{
+        let binary = resolve_binary(&self.bart_binary)?;
+        let o = Command::new(&binary).arg("version").output()
+            .map_err(|e| format!("failed to run {:?} version: {}",binary,e))?;
+        if !o.status.success(){return Err(format!("{:?} version returned an error",binary))}
+        let response = String::from_utf8_lossy(&o.stdout);
+        let version = BartVersion::parse(&response)
+            .ok_or(format!("cannot make sense of bart version string {}",response.trim()))?;
+        self.check_capabilities(&version)?;
+        return Ok(version);
+    }
+
+    /*
+    rejects settings that need a newer bart than version. Only the fista solver has a known minimum
+    version so far; the other pics flags (-R regularizers, -m, -u, -C, -s, -b, -p) are not checked
+    */
+    pub fn check_capabilities(&self,version:&BartVersion) -> Result<(),String>{
+        let mut required = Vec::<(&str,BartVersion)>::new();
+        if self.solver == PicsSolver::Fista {required.push(("the fista solver (--fista)",BartVersion::new(0,8,0)))}
+        let unsupported:Vec<String> = required.iter().filter(|(_,v)| version < v)
+            .map(|(feature,v)| format!("{} needs bart {} or newer",feature,v)).collect();
+        if unsupported.is_empty() {return Ok(())}
+        return Err(format!("bart {} at {} cannot run these settings: {}",version,self.bart_binary,unsupported.join("; ")));
+    }
+
+    /* estimate coil sensitivities from the fully sampled center of the k-space with ESPIRiT */
+    pub fn set_espirit_coil_sens(&mut self,sens_cfl:&str,kspace_cfl:&str){
+        self.coil_sensitivity = sens_cfl.to_string();
+        println!("estimating espirit sens");
+        BartCommand::ecalib(&self.bart_binary,self.calibration_size,self.espirit_threshold,kspace_cfl,sens_cfl).run();
+    }
+
+    /*
+    prepare_coil_sens
+    makes sure a coil sensitivity map matching the k-space exists. Unit and ESPIRiT maps are computed
+    once per run at shared_sens_cfl and reused by every volume. Volume managers running at the same time
+    coordinate through a lock file so that only one of them computes the map. A lock left behind by a
+    killed job is taken over after COIL_SENS_LOCK_TIMEOUT
+    */
+    pub fn prepare_coil_sens(&mut self,kspace_cfl:&str,shared_sens_cfl:&str){
+        let kspace_dims = cfl::get_dims_trimmed(Path::new(kspace_cfl));
+        let coils = kspace_dims.get(3).cloned().unwrap_or(1);
+        if self.sensitivity_mode == SensitivityMode::Unit && coils > 1 {
+            panic!("{} has {} coils. Unit coil sensitivities would combine them as one coil, use Espirit or File",kspace_cfl,coils);
+        }
+        if self.sensitivity_mode == SensitivityMode::File {
+            if self.coil_sensitivity.is_empty(){panic!("coil sensitivity mode is File but no coil_sensitivity is set")}
+            let sens_dims = cfl::get_dims_trimmed(Path::new(&self.coil_sensitivity));
+            if sens_dims != kspace_dims {panic!("coil sensitivity dims {:?} don't match k-space dims {:?}",sens_dims,kspace_dims)}
+            return;
         }
+        let sens = Path::new(shared_sens_cfl).with_extension("");
+        let lock = sens.with_extension("lock");
+        let is_valid = |sens:&Path| sens.with_extension("hdr").exists() && cfl::get_dims_trimmed(sens) == kspace_dims;
+        loop {
+            if !lock.exists() && is_valid(&sens){break}
+            match LockFile::acquire(&lock,COIL_SENS_LOCK_TIMEOUT){
+                Some(_guard) => {
+                    if !is_valid(&sens){
+                        match self.sensitivity_mode {
+                            SensitivityMode::Espirit => self.set_espirit_coil_sens(sens.to_str().unwrap(),kspace_cfl),
+                            _ => self.set_unit_coil_sens(sens.to_str().unwrap(),kspace_cfl),
+                        }
+                    }
+                    break;
+                }
+                None => {
+                    println!("waiting for coil sensitivities from another volume ...");
+                    std::thread::sleep(std::time::Duration::from_secs(5));
+                }
+            }
+        }
+        self.coil_sensitivity = sens.to_str().unwrap().to_string();
+    }
+}
+
+/* a lock older than this was left behind by a job that was killed and is taken over */
+const COIL_SENS_LOCK_TIMEOUT:Duration = Duration::from_secs(3600);
+
+/* lock file that is removed when it goes out of scope, including when a panic unwinds */
+struct LockFile{
+    path:PathBuf,
+}
+
+impl LockFile{
+    /* None if someone else holds the lock. Locks older than stale_after are removed first */
+    fn acquire(path:&Path,stale_after:Duration) -> Option<LockFile>{
+        let age = std::fs::metadata(path).and_then(|m| m.modified()).ok().and_then(|t| t.elapsed().ok());
+        if age.is_some_and(|age| age > stale_after){
+            println!("removing stale lock {:?}",path);
+            remove_file(path).ok();
+        }
+        return match OpenOptions::new().write(true).create_new(true).open(path){
+            Ok(_) => Some(LockFile{path:path.to_path_buf()}),
+            Err(_) => None
+        };
+    }
+}
+
+impl Drop for LockFile{
+    fn drop(&mut self){
+        remove_file(&self.path).ok();
+    }
+}
+
+/*
+bart_pics
+runs pics with its output captured to log_file. The iteration history found in the log is saved
+next to it as <log name>-convergence.json and returned
+*/
+pub fn bart_pics(kspace_cfl:&str,img_cfl:&str,shared_sens_cfl:&str,log_file:&Path,settings:&mut BartPicsSettings) -> Convergence{
+
+    if settings.sample_weights && !Path::new(&weights_cfl(kspace_cfl)).with_extension("cfl").exists(){
+        panic!("sample weights are on but {} doesn't exist",weights_cfl(kspace_cfl));
+    }
+    settings.prepare_coil_sens(kspace_cfl,shared_sens_cfl);
+    let log = settings.pics_command(kspace_cfl,&settings.coil_sensitivity,img_cfl).run_logged(log_file);
+    let convergence = Convergence::from_log(&log);
+    convergence.save_next_to(log_file);
+    return convergence;
+}
+
+/* sample weights written next to zero-filled k-space by Mrd::write_zero_filled_volume_cfl */
+pub fn weights_cfl(kspace_cfl:&str) -> String{
+    return format!("{}_weights",kspace_cfl);
+}
+
+/*
+copies the sample weights of a k-space, when it has any, next to k-space cut from it along the readout
+(slabs, crops). The pattern only covers the phase encodes so it applies unchanged
+*/
+pub fn copy_weights(from_kspace_cfl:&str,to_kspace_cfl:&str){
+    let weights = weights_cfl(from_kspace_cfl);
+    if !Path::new(&weights).with_extension("cfl").exists() {return}
+    let to_weights = weights_cfl(to_kspace_cfl);
+    for ext in ["cfl","hdr"]{
+        std::fs::copy(Path::new(&weights).with_extension(ext),Path::new(&to_weights).with_extension(ext)).expect("cannot copy sample weights");
+    }
+}
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq,PartialOrd,Ord,Serialize,Deserialize)]
+pub struct BartVersion{
+    pub major:u32,
+    pub minor:u32,
+    pub patch:u32,
+}
+
+impl BartVersion{
+    pub fn new(major:u32,minor:u32,patch:u32) -> BartVersion{
+        return BartVersion{major:major,minor:minor,patch:patch};
+    }
+
+    /* understands "v0.7.00", "0.9.00-12-gabcdef" and "v0.7.00-dirty" */
+    pub fn parse(version_str:&str) -> Option<BartVersion>{
+        let v = version_str.trim().trim_start_matches('v');
+        let v = v.split(|c:char| c == '-' || c.is_whitespace()).next()?;
+        let nums:Vec<u32> = v.split('.').flat_map(|n| n.parse()).collect();
+        if nums.len() != 3 {return None}
+        return Some(BartVersion::new(nums[0],nums[1],nums[2]));
+    }
+}
+
+impl fmt::Display for BartVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f,"v{}.{}.{:02}",self.major,self.minor,self.patch)
+    }
+}
+
+/* find the binary (as a path or on PATH) and make sure it is executable */
+fn resolve_binary(binary:&str) -> Result<PathBuf,String>{
+    let candidates:Vec<PathBuf> = match binary.contains('/'){
+        true => vec![PathBuf::from(binary)],
+        false => std::env::var("PATH").unwrap_or_default().split(':').map(|dir| Path::new(dir).join(binary)).collect()
+    };
+    let found = candidates.into_iter().find(|p| p.is_file())
+        .ok_or(format!("bart binary {} not found",binary))?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = std::fs::metadata(&found).map_err(|e| e.to_string())?.permissions().mode();
+        if mode & 0o111 == 0 {return Err(format!("bart binary {:?} is not executable",found))}
+    }
+    return Ok(found);
+}
+
+/* One iteration of an iterative bart algorithm as reported at debug level 5 */
+#[derive(Debug,Clone,PartialEq,Serialize,Deserialize)]
+pub struct IterationRecord{
+    pub iteration:usize,
+    pub objective:Option<f64>,
+    pub step_size:Option<f64>,
+}
+
+#[derive(Debug,Clone,PartialEq,Default,Serialize,Deserialize)]
+pub struct Convergence{
+    pub iterations:Vec<IterationRecord>,
+}
+
+impl Convergence{
+
+    /*
+    from_log
+    picks the iteration history out of bart output. Two forms are understood:
+        "#It 012: 1.234e+02 [step]" lines from ist/fista/conjgrad
+        tab separated tables with a header row naming the columns (ADMM prints iter, obj, rho ...)
+    A global "step size: x" line is used for iterations that don't report their own
+    */
+    pub fn from_log(log:&str) -> Convergence{
+        let mut iterations = Vec::<IterationRecord>::new();
+        let mut default_step:Option<f64> = None;
+        let mut table_header:Option<Vec<String>> = None;
+        log.lines().for_each(|line|{
+            let trimmed = line.trim();
+            let lower = trimmed.to_ascii_lowercase();
+            if let Some(step) = lower.strip_prefix("step size:") {
+                default_step = step.trim().parse().ok();
+                return;
+            }
+            if let Some(rest) = trimmed.strip_prefix("#It") {
+                let (it,vals) = match rest.split_once(':'){
+                    Some(split) => split,
+                    None => return
+                };
+                let vals:Vec<f64> = vals.split_whitespace().flat_map(|v| v.parse()).collect();
+                if let Ok(iteration) = it.trim().parse(){
+                    iterations.push(IterationRecord{
+                        iteration:iteration,
+                        objective:vals.first().cloned(),
+                        step_size:vals.get(1).cloned().or(default_step)
+                    });
+                }
+                return;
+            }
+            let cols:Vec<&str> = trimmed.split_whitespace().collect();
+            if cols.first().map(|c| c.to_ascii_lowercase()) == Some("iter".to_string()) {
+                table_header = Some(cols.iter().map(|c| c.to_ascii_lowercase()).collect());
+                return;
+            }
+            if let Some(header) = &table_header {
+                let vals:Vec<f64> = cols.iter().flat_map(|v| v.parse()).collect();
+                if vals.len() != header.len() || vals.is_empty() {return}
+                let column = |names:&[&str]| header.iter().position(|h| names.contains(&h.as_str())).map(|i| vals[i]);
+                iterations.push(IterationRecord{
+                    iteration:vals[0] as usize,
+                    objective:column(&["obj","objective"]),
+                    step_size:column(&["tau","rho","step"]).or(default_step)
+                });
+            }
+        });
+        return Convergence{iterations:iterations};
+    }
+
+    pub fn to_file(&self,dest_path:&str){
+        let s = serde_json::to_string_pretty(&self).expect("trouble serializing data struct");
+        utils::write_to_file(dest_path,"json",&s);
+    }
+
+    /* saved as <log name>-convergence.json */
+    pub fn save_next_to(&self,log_file:&Path){
+        let stem = log_file.file_stem().unwrap().to_str().unwrap();
+        let convergence_file = log_file.with_file_name(format!("{}-convergence",stem));
+        self.to_file(convergence_file.to_str().unwrap());
+    }
+}
+
+/*
+BartCommand
+argument vector for one call to a bart tool. Commands are built up front so they can be checked,
+logged and replayed, and every tool runs through the same runner
+*/
+#[derive(Debug,Clone,PartialEq)]
+pub struct BartCommand{
+    binary:String,
+    tool:String,
+    args:Vec<String>,
+}
+
+impl BartCommand{
+    pub fn new(binary:&str,tool:&str) -> BartCommand{
+        return BartCommand{binary:binary.to_string(),tool:tool.to_string(),args:Vec::new()};
+    }
+
+    pub fn arg<T:ToString>(&mut self,arg:T) -> &mut Self{
+        self.args.push(arg.to_string());
+        return self;
+    }
+
+    pub fn args<T:ToString>(&mut self,args:Vec<T>) -> &mut Self{
+        args.iter().for_each(|a| self.args.push(a.to_string()));
+        return self;
+    }
+
+    /* only add a flag when it is switched on, instead of passing an empty argument */
+    pub fn flag_if(&mut self,condition:bool,flag:&str) -> &mut Self{
+        if condition {self.args.push(flag.to_string())}
+        return self;
+    }
+
+    pub fn ones(binary:&str,dims:&[usize],output:&str) -> BartCommand{
+        let mut cmd = BartCommand::new(binary,"ones");
+        cmd.arg(dims.len()).args(dims.to_vec()).arg(output);
+        return cmd;
+    }
+
+    pub fn ecalib(binary:&str,calibration_size:u32,threshold:f32,kspace:&str,sens:&str) -> BartCommand{
+        let mut cmd = BartCommand::new(binary,"ecalib");
+        cmd.arg(format!("-r{}",calibration_size))
+        .arg(format!("-t{}",threshold))
+        .arg("-m1")
+        .arg(kspace).arg(sens);
+        return cmd;
     }
-    //settings.to_file(bart_pics_settings);
-    let mut cmd = Command::new(&settings.bart_binary);
-    let scale = if settings.respect_scaling { "-S" } else { "" };
-    let debug = if settings.debug {"-d5"} else {""};
-    cmd.arg("pics");
-    cmd.arg(format!("-{}",settings.algorithm));
-    cmd.arg(format!("-r{}",settings.regularization));
-    cmd.arg(format!("-i{}",settings.max_iter));
-    cmd.arg(scale);
-    cmd.arg(debug);
-    cmd.arg(kspace_cfl).arg(&settings.coil_sensitivity).arg(img_cfl);
 
-    // cmd.arg("normalize");
-    // cmd.arg(kspace_cfl).arg(&settings.coil_sensitivity).arg(img_cfl);
+    /* centered fft over the dimensions in the bitmask flags */
+    pub fn fft(binary:&str,inverse:bool,flags:u32,input:&str,output:&str) -> BartCommand{
+        let mut cmd = BartCommand::new(binary,"fft");
+        cmd.flag_if(inverse,"-i").arg(flags).arg(input).arg(output);
+        return cmd;
+    }
+
+    pub fn scale(binary:&str,factor:f32,input:&str,output:&str) -> BartCommand{
+        let mut cmd = BartCommand::new(binary,"scale");
+        cmd.arg(factor).arg(input).arg(output);
+        return cmd;
+    }
+
+    pub fn tool(&self) -> &str{
+        return &self.tool;
+    }
 
-    println!("{:?}",cmd);
-    let proc = cmd.spawn().expect("failed to launch bart pics");
-    let results = proc.wait_with_output().expect("failed to wait on output");
-    if !results.status.success(){panic!("bart pics failed!");}    
+    /* full argument vector including the binary */
+    pub fn argv(&self) -> Vec<String>{
+        let mut argv = vec![self.binary.clone(),self.tool.clone()];
+        argv.extend(self.args.iter().cloned());
+        return argv;
+    }
+
+    pub fn to_command(&self) -> Command{
+        let mut cmd = Command::new(&self.binary);
+        cmd.arg(&self.tool).args(&self.args);
+        return cmd;
+    }
+
+    pub fn run(&self){
+        self.execute(None);
+    }
+
+    /*
+    run_logged
+    runs with stdout and stderr captured into log_file (headed by the command line) and returns the log.
+    When bart fails, the end of the log is part of the panic message
+    */
+    pub fn run_logged(&self,log_file:&Path) -> String{
+        return self.execute(Some(log_file));
+    }
+
+    /* spawns bart and waits on it. Output goes to the terminal unless it is logged */
+    fn execute(&self,log_file:Option<&Path>) -> String{
+        println!("{}",self);
+        let mut cmd = self.to_command();
+        if let Some(log_file) = log_file{
+            println!("logging bart output to {:?}",log_file);
+            let mut log = File::create(log_file).expect("cannot create bart log file");
+            writeln!(log,"{}",self).expect("trouble writing to bart log");
+            let stderr = log.try_clone().expect("cannot share bart log file handle");
+            cmd.stdout(Stdio::from(log)).stderr(Stdio::from(stderr));
+        }
+        let status = cmd.status().unwrap_or_else(|_| panic!("failed to launch bart {}",self.tool));
+        let log = match log_file{
+            Some(log_file) => std::fs::read_to_string(log_file).expect("trouble reading bart log"),
+            None => String::new(),
+        };
+        if !status.success(){
+            match log_file{
+                Some(log_file) => {
+                    let lines:Vec<&str> = log.lines().collect();
+                    let tail = lines[lines.len().saturating_sub(20)..].join("\n");
+                    panic!("bart {} failed! end of {:?}:\n{}",self.tool,log_file,tail);
+                }
+                None => panic!("bart {} failed!",self.tool),
+            }
+        }
+        return log;
+    }
+}
+
+impl fmt::Display for BartCommand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f,"{}",self.argv().join(" "))
+    }
 }
 
 #[test]
@@ -160,6 +664,112 @@ fn test(){
     "0", "/home/wyatt/petableCS_stream/stream_CS480_8x_pa18_pb54",
     "480", "./test_cfl");
 
-    bart_pics("./test_cfl","./img_cfl",&mut s);
+    bart_pics("./test_cfl","./img_cfl","./coil_sens",Path::new("./bart.log"),&mut s);
 
-}
\ No newline at end of file
+}
+#[test]
+fn test_sens_settings(){
+    // settings written before sensitivity modes existed still load
+    let old = "bart_binary = \"bart\"\nmax_iter = 2\nalgorithm = \"l1\"\nrespect_scaling = true\n\
+        regularization = 0.005\ndebug = true\ncoil_sensitivity = \"\"\nimage_scale_histo_percent = 0.9995\n";
+    let s:BartPicsSettings = toml::from_str(old).unwrap();
+    assert_eq!(s.sensitivity_mode,SensitivityMode::Unit);
+    assert_eq!(s.calibration_size,24);
+    let mut s = BartPicsSettings::default();
+    s.sensitivity_mode = SensitivityMode::Espirit;
+    let r:BartPicsSettings = toml::from_str(&toml::to_string(&s).unwrap()).unwrap();
+    assert_eq!(r.sensitivity_mode,SensitivityMode::Espirit);
+}
+
+#[test]
+fn test_regularizers(){
+    let mut s = BartPicsSettings::default();
+    assert_eq!(s.regularization_args(),vec!["-l1","-r0.005"]);
+    s.add_regularizer(Regularizer::new(RegularizerKind::Wavelet,7,0,0.005))
+    .add_regularizer(Regularizer::new(RegularizerKind::TotalVariation,7,0,0.001));
+    s.solver = PicsSolver::Admm;
+    s.admm_rho = Some(0.05);
+    assert_eq!(s.regularization_args(),vec!["-RW:7:0:0.005","-RT:7:0:0.001"]);
+    assert_eq!(s.solver_args(),vec!["-m","-u0.05"]);
+    let r:BartPicsSettings = toml::from_str(&toml::to_string(&s).unwrap()).unwrap();
+    assert_eq!(r.regularizers,s.regularizers);
+}
+
+#[test]
+fn test_bart_command(){
+    let mut s = BartPicsSettings::default();
+    s.respect_scaling = false;
+    s.debug = false;
+    let cmd = s.pics_command("kspace","sens","img");
+    assert_eq!(cmd.argv(),vec!["bart","pics","-l1","-r0.005","-i36","kspace","sens","img"]);
+    s.debug = true;
+    assert_eq!(s.pics_command("k","s","i").to_string(),"bart pics -l1 -r0.005 -i36 -d5 k s i");
+    assert_eq!(BartCommand::ones("bart",&[480,256,256],"sens").argv(),vec!["bart","ones","3","480","256","256","sens"]);
+    assert_eq!(BartCommand::fft("bart",true,7,"k","i").argv(),vec!["bart","fft","-i","7","k","i"]);
+    s.sample_weights = true;
+    assert_eq!(s.pics_command("k","s","i").to_string(),"bart pics -l1 -r0.005 -i36 -d5 -p k_weights k s i");
+    // a singleton readout keeps its place in the sens map
+    let kspace = std::env::temp_dir().join("cs_reco_test_singleton_kspace");
+    cfl::write(&kspace,&vec![0.0;2*4*3],&[1,4,3]);
+    assert_eq!(s.unit_sens_command(kspace.to_str().unwrap(),"sens").argv(),vec!["bart","ones","3","1","4","3","sens"]);
+}
+
+#[test]
+fn test_convergence(){
+    let log = "bart pics -d5 k s i\nStep size: 0.5\n#It 000: 12.5\n#It 001: 6.25 0.25\nDone.\n\
+        ### ADMM ###\niter\tcgiter\tobj\trho\n  2\t  5\t3.0\t0.05\n";
+    let c = Convergence::from_log(log);
+    assert_eq!(c.iterations,vec![
+        IterationRecord{iteration:0,objective:Some(12.5),step_size:Some(0.5)},
+        IterationRecord{iteration:1,objective:Some(6.25),step_size:Some(0.25)},
+        IterationRecord{iteration:2,objective:Some(3.0),step_size:Some(0.05)},
+    ]);
+    // stdout and stderr both land in the log
+    let log_file = std::env::temp_dir().join("cs_reco_test_bart.log");
+    let mut cmd = BartCommand::new("sh","-c");
+    cmd.arg("echo '#It 000: 1.5'; echo oops 1>&2");
+    let log = cmd.run_logged(&log_file);
+    assert!(log.contains("oops"));
+    assert_eq!(Convergence::from_log(&log).iterations.len(),1);
+}
+
+#[cfg(unix)]
+#[test]
+fn test_bart_version(){
+    assert_eq!(BartVersion::parse("v0.7.00\n"),Some(BartVersion::new(0,7,0)));
+    assert_eq!(BartVersion::parse("0.9.00-12-gabcdef"),Some(BartVersion::new(0,9,0)));
+    assert_eq!(BartVersion::parse("nonsense"),None);
+    // fake bart that reports an old version
+    let bart = std::env::temp_dir().join("cs_reco_test_bart_version");
+    std::fs::write(&bart,"#!/bin/sh\necho v0.7.00\n").unwrap();
+    use std::os::unix::fs::PermissionsExt;
+    std::fs::set_permissions(&bart,std::fs::Permissions::from_mode(0o755)).unwrap();
+    let mut s = BartPicsSettings::default();
+    s.set_bart_binary(bart.to_str().unwrap());
+    assert_eq!(s.probe_bart(),Ok(BartVersion::new(0,7,0)));
+    s.solver = PicsSolver::Fista;
+    assert!(s.probe_bart().is_err());
+}
+
+#[test]
+fn test_lock_file(){
+    let path = std::env::temp_dir().join("cs_reco_test_sens.lock");
+    remove_file(&path).ok();
+    let guard = LockFile::acquire(&path,COIL_SENS_LOCK_TIMEOUT).unwrap();
+    assert!(LockFile::acquire(&path,COIL_SENS_LOCK_TIMEOUT).is_none());
+    drop(guard);
+    assert!(!path.exists());
+    // the lock is released when the holder panics
+    let p = path.clone();
+    assert!(std::panic::catch_unwind(move ||{
+        let _guard = LockFile::acquire(&p,COIL_SENS_LOCK_TIMEOUT).unwrap();
+        panic!("coil sensitivity estimation failed");
+    }).is_err());
+    assert!(!path.exists());
+    // a stale lock left by a killed job is taken over
+    File::create(&path).unwrap();
+    std::thread::sleep(Duration::from_millis(20));
+    assert!(LockFile::acquire(&path,COIL_SENS_LOCK_TIMEOUT).is_none());
+    assert!(LockFile::acquire(&path,Duration::from_millis(10)).is_some());
+    assert!(!path.exists());
+}
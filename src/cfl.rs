@@ -4,16 +4,39 @@ use std::path::{Path,PathBuf};
 use std::fs::{File};
 use std::io::{Read,Write};
 use byteorder::{ByteOrder,BigEndian,LittleEndian};
-use ndarray::{s,Array3,Array4,Order,Axis};
+use ndarray::{s,Array3,Array5,Order,Axis};
 
 pub fn get_dims(path:&Path) -> Vec<usize>{
+    let dims = get_all_dims(path);
+    let non_singleton:Vec<usize> = dims.into_iter().filter(|dimension| *dimension != 1).collect();
+    return non_singleton;
+}
+
+/* dimensions as written in the header, singletons included */
+pub fn get_all_dims(path:&Path) -> Vec<usize>{
     let p = path.to_str().unwrap();
     let h = load_cfl_header(p);
     let d = h.get("# Dimensions").expect("Couldn't find # dimesions").to_owned();
     let dim_str:Vec<&str> = d.split_whitespace().collect();
-    let dims:Vec<usize> = dim_str.iter().flat_map(|str| str.to_string().parse()).collect();
-    let non_singleton:Vec<usize> = dims.into_iter().filter(|dimension| *dimension != 1).collect();
-    return non_singleton;
+    return dim_str.iter().flat_map(|str| str.to_string().parse()).collect();
+}
+
+/*
+dimensions up to the last non-singleton one. Singletons before it keep their place (unlike get_dims),
+so [1,ky,kz] stays 3-D and headers padded to 5 or 16 dims compare equal
+*/
+pub fn get_dims_trimmed(path:&Path) -> Vec<usize>{
+    let mut dims = get_all_dims(path);
+    while dims.len() > 1 && dims.last() == Some(&1) {dims.pop();}
+    return dims;
+}
+
+/* [x,y,z] and the coil count (bart dim 3) of an image. Singleton dimensions keep their place */
+pub fn volume_dims(path:&Path) -> ([usize;3],usize){
+    let mut dims = get_all_dims(path);
+    while dims.len() < 4 {dims.push(1)}
+    if dims[4..].iter().any(|d| *d != 1) {panic!("{:?} has dims {:?}. Only the spatial and coil dimensions can be larger than 1",path,dims)}
+    return ([dims[0],dims[1],dims[2]],dims[3]);
 }
 
 pub fn load_cfl_header(path:&str) -> HashMap<String,String>{
@@ -30,9 +53,24 @@ pub fn load_cfl_header(path:&str) -> HashMap<String,String>{
     return h;
 }
 
+/* write interleaved complex floats to a cfl/hdr pair */
+pub fn write(cfl:&Path,data:&[f32],dims:&[usize]){
+    let mut numel = 1;
+    dims.iter().for_each(|d| numel *= d);
+    if 2*numel != data.len(){panic!("{} floats cannot fit into complex dims {:?}",data.len(),dims)}
+    let mut raw_f = File::create(cfl.with_extension("cfl")).expect("cannot create file");
+    let mut header_f = File::create(cfl.with_extension("hdr")).expect("cannot create file");
+    let mut bytebuff:Vec<u8> = vec![0;data.len()*4];
+    LittleEndian::write_f32_into(data,&mut bytebuff);
+    raw_f.write_all(&bytebuff).expect("a problem occured writing to cfl raw");
+    let mut hdr_dims = dims.to_vec();
+    while hdr_dims.len() < 5 {hdr_dims.push(1)}
+    let hdr_str = format!("# Dimensions\n{}",utils::vec_to_string(&hdr_dims));
+    header_f.write_all(hdr_str.as_bytes()).expect("a problem occured writing to cfl header");
+}
+
 pub fn to_civm_raw_u16(cfl:&Path,output_dir:&Path,volume_label:&str,raw_prefix:&str,scale:f32){
-    let dims = get_dims(cfl);
-    if dims.len() !=3 {panic!("we don't know how to write data {}-D data!",dims.len())}
+    let (dims,_) = volume_dims(cfl);
     let mag = Array3::from_shape_vec((dims[2],dims[1],dims[0]),to_magnitude(cfl)).expect("raw floats cannot fit into shape");
     let numel_per_img = dims[1]*dims[0];
     let mut byte_buff:Vec<u8> = vec![0;2*numel_per_img];
@@ -59,19 +97,29 @@ pub fn load(cfl:&Path) -> Vec<f32>{
 }
 
 pub fn to_magnitude(cfl:&Path) -> Vec<f32>{
-    let dims = get_dims(cfl);
-    let mut complex = Array4::from_shape_vec((dims[2],dims[1],dims[0],2),load(cfl)).expect("cannot fit data vector in ndarray");
+    let (dims,n_coils) = volume_dims(cfl);
+    let mut complex = Array5::from_shape_vec((n_coils,dims[2],dims[1],dims[0],2),load(cfl)).expect("cannot fit data vector in ndarray");
     let square = |x:&mut f32| *x = (*x).powi(2);
     // magnitude is calculated from complex values
     // "square root of the sum of the squares"
-    complex.slice_mut(s![..,..,..,0]).map_inplace(square);
-    complex.slice_mut(s![..,..,..,1]).map_inplace(square);
-    let mut mag = complex.sum_axis(Axis(3));
+    // per-coil images are combined the same way (root-sum-of-squares over the coil dimension)
+    complex.slice_mut(s![..,..,..,..,0]).map_inplace(square);
+    complex.slice_mut(s![..,..,..,..,1]).map_inplace(square);
+    let mut mag = complex.sum_axis(Axis(4)).sum_axis(Axis(0));
     mag.mapv_inplace(f32::sqrt);
     let f = mag.to_shape((dims[2]*dims[1]*dims[0],Order::RowMajor)).expect("cannot flatten array");
     return f.to_vec();
 }
 
+/* magnitude of the middle slice along the third dimension, returned with its [x,y] size */
+pub fn central_slice(cfl:&Path) -> (Vec<f32>,[usize;2]){
+    let (dims,_) = volume_dims(cfl);
+    let mag = to_magnitude(cfl);
+    let n = dims[0]*dims[1];
+    let z = dims[2]/2;
+    return (mag[z*n..(z+1)*n].to_vec(),[dims[0],dims[1]]);
+}
+
 pub fn find_u16_scale(cfl:&Path,histo_percent:f64) -> f32{
     let mag = to_magnitude(cfl);
     return u16_scale_from_vec(&mag,histo_percent);
@@ -96,4 +144,19 @@ fn test_write(){
     let raw_prefix = "t9imx";
     let scale = find_u16_scale(cfl,0.999500);
     to_civm_raw_u16(&cfl,&out,label,raw_prefix,scale);
-}
\ No newline at end of file
+}
+#[test]
+fn test_coil_combine(){
+    let cfl = std::env::temp_dir().join("cs_reco_test_coil_combine");
+    // 2x2x2 image with 2 coils: coil 0 is 3 everywhere, coil 1 is 4i everywhere
+    let mut data:Vec<f32> = [3.0,0.0].repeat(8);
+    data.extend([0.0,4.0].repeat(8));
+    write(&cfl,&data,&[2,2,2,2]);
+    assert_eq!(get_dims(&cfl),vec![2,2,2,2]);
+    assert_eq!(to_magnitude(&cfl),vec![5.0;8]);
+    // a singleton spatial dimension leaves the coils in bart dim 3
+    write(&cfl,&data,&[2,1,4,2]);
+    assert_eq!(volume_dims(&cfl),([2,1,4],2));
+    assert_eq!(to_magnitude(&cfl),vec![5.0;8]);
+    assert_eq!(central_slice(&cfl),(vec![5.0;2],[2,1]));
+}
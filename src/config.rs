@@ -3,8 +3,13 @@ use serde::{Deserialize, Serialize};
 use std::path::{Path, PathBuf};
 use whoami;
 use serde_json;
-use crate::bart_wrapper::BartPicsSettings;
+use crate::bart_wrapper::{BartPicsSettings,SensitivityMode};
 use crate::resource::Host;
+use crate::mrd::{IncompleteMrdPolicy,Mrd,ZeroFillMode};
+use crate::recon_engine::{EngineSettings,ReconEngine};
+use crate::slab_recon::{SlabSettings,SlabEngine};
+use crate::pe_table::PetableSettings;
+use crate::volume_index::PetableMap;
 
 #[derive(Serialize,Deserialize,Debug)]
 pub struct Recon{
@@ -14,6 +19,17 @@ pub struct Recon{
     pub engine_work_dir:PathBuf,
     pub recon_person:String,
     pub n_volumes:Option<usize>,
+    /* total volumes of the run when known up front. Fixes the width of the volume labels */
+    #[serde(default)]
+    pub expected_volumes:Option<usize>,
+    /* zero padding of the volume labels, set once the first mrd arrives */
+    #[serde(default)]
+    pub label_width:Option<usize>,
+    #[serde(default)]
+    pub bart_version:Option<String>,
+    /* phase encode tables that differ from the run table for some volumes */
+    #[serde(default)]
+    pub pe_tables:PetableMap,
     pub scanner:Scanner,
     pub project:ProjectSettings,
 }
@@ -29,11 +45,28 @@ pub struct Scanner{
     pub image_source_tag:String,
 }
 
-#[derive(Serialize,Deserialize,Debug)]
+#[derive(Serialize,Deserialize,Debug,Clone)]
 pub struct ProjectSettings{
     pub label:String,
     pub project_code:String,
+    #[serde(default)]
+    pub incomplete_mrd:IncompleteMrdPolicy,
+    /* how repeated phase encodes are combined when zero-filling */
+    #[serde(default)]
+    pub zero_fill:ZeroFillMode,
+    /* receiver channels, for mrd files whose trailer doesn't give NO_RECEIVERS */
+    #[serde(default)]
+    pub channels:Option<usize>,
+    // tables must come after plain values in toml
     pub recon_settings:BartPicsSettings,
+    #[serde(default)]
+    pub engine:EngineSettings,
+    /* reconstruct in independent slabs along the readout when given */
+    #[serde(default)]
+    pub slabs:Option<SlabSettings>,
+    /* table sizes and compression for stream tables that don't carry them */
+    #[serde(default)]
+    pub pe_table:PetableSettings,
 }
 
 // Recon::new("grumpy","test_runno","/some/vol_index.txt","5xfad")
@@ -59,6 +92,10 @@ impl Recon{
             project:ProjectSettings::open(project),
             specimen_id:specimen_id.to_string(),
             n_volumes:None,
+            expected_volumes:None,
+            label_width:None,
+            bart_version:None,
+            pe_tables:PetableMap::default(),
         };
         let s = serde_json::to_string_pretty(&r).expect("cannot serialize struct");
         utils::write_to_file(p.to_str().unwrap(),"json",&s);
@@ -83,6 +120,12 @@ impl Recon{
         return Path::new(&self.run_number).with_extension("json");
     }
 
+    /* write back changes made after the recon was created */
+    pub fn save(&self){
+        let s = serde_json::to_string_pretty(&self).expect("cannot serialize struct");
+        utils::write_to_file(self.path().to_str().unwrap(),"json",&s);
+    }
+
 }
 
 impl ProjectSettings{
@@ -99,11 +142,49 @@ impl ProjectSettings{
         let project_settings = ProjectSettings{
             label:label.to_string(),
             project_code:"22.project.01".to_string(),
+            incomplete_mrd:IncompleteMrdPolicy::default(),
+            zero_fill:ZeroFillMode::default(),
+            channels:None,
             recon_settings:BartPicsSettings::default(),
+            engine:EngineSettings::default(),
+            slabs:None,
+            pe_table:Default::default(),
         };
         utils::write_to_file(label,"toml",&toml::to_string(&project_settings).expect("cannot serialize struct"));
         return project_settings;
     }
+
+    pub fn to_file(&self,path:&str){
+        utils::write_to_file(path,"toml",&toml::to_string(&self).expect("cannot serialize struct"));
+    }
+
+    /*
+    unit sensitivities make pics treat every receiver as the same coil, mixing their phases into one
+    image. More than one channel needs ESPIRiT or a sensitivity file
+    */
+    pub fn validate(&self) -> Result<(),String>{
+        let channels = self.channels.unwrap_or(1);
+        if channels > 1 && self.engine.uses_bart() && self.recon_settings.sensitivity_mode == SensitivityMode::Unit {
+            return Err(format!("{} receiver channels cannot be reconstructed with Unit coil sensitivities. Use Espirit or File",channels));
+        }
+        return Ok(());
+    }
+
+    /* opens an mrd with the project's channel count and zero-fill mode */
+    pub fn open_mrd(&self,path:&str) -> Mrd{
+        let mut mrd = Mrd::new(path);
+        if let Some(channels) = self.channels {mrd.set_channels(channels)}
+        mrd.zero_fill_mode = self.zero_fill;
+        return mrd;
+    }
+
+    /* the engine that reconstructs each volume, split into slabs when configured */
+    pub fn recon_engine(&self) -> Box<dyn ReconEngine>{
+        return match &self.slabs{
+            Some(slabs) => Box::new(SlabEngine::new(self.engine.clone(),self.recon_settings.clone(),slabs.clone())),
+            None => self.engine.build(&self.recon_settings),
+        }
+    }
 }
 
 impl Scanner {
@@ -134,4 +215,32 @@ impl Scanner {
     pub fn host(&self) -> Host{
         Host::new(&self.username,&self.hostname)
     }
-}
\ No newline at end of file
+}
+#[test]
+fn test_project_settings_toml(){
+    let p = ProjectSettings{
+        label:"test".to_string(),
+        project_code:"22.project.01".to_string(),
+        incomplete_mrd:IncompleteMrdPolicy::Partial,
+        zero_fill:ZeroFillMode::KeepLast,
+        channels:Some(4),
+        recon_settings:BartPicsSettings::default(),
+        engine:EngineSettings::ZeroFilled,
+        slabs:Some(SlabSettings{slab_thickness:1,parallel_jobs:8}),
+        pe_table:PetableSettings{size_ky:Some(480),size_kz:Some(240),compression:None},
+    };
+    let s = toml::to_string(&p).expect("cannot serialize struct");
+    let r:ProjectSettings = toml::from_str(&s).expect("cannot deserialize struct");
+    assert_eq!(r.incomplete_mrd,IncompleteMrdPolicy::Partial);
+    assert_eq!(r.zero_fill,ZeroFillMode::KeepLast);
+    assert_eq!(r.channels,Some(4));
+    assert_eq!(r.engine,EngineSettings::ZeroFilled);
+    assert_eq!(r.slabs,p.slabs);
+    assert_eq!(r.pe_table,p.pe_table);
+    // 4 channels with the default unit sensitivities
+    let mut r = r;
+    r.engine = EngineSettings::BartPics;
+    assert!(r.validate().is_err());
+    r.recon_settings.sensitivity_mode = SensitivityMode::Espirit;
+    assert!(r.validate().is_ok());
+}
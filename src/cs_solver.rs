@@ -0,0 +1,416 @@
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::fs::File;
+use std::io::Write;
+use rayon::prelude::*;
+use rustfft::num_complex::Complex32;
+use crate::cfl;
+use crate::fft::{fft_axes,for_each_line,to_complex,to_interleaved};
+use crate::bart_wrapper::{Convergence,IterationRecord};
+
+/*
+CsSettings
+compressed sensing reconstruction of cartesian 3D k-space without bart. FISTA minimizes
+    0.5||M F S x - y||^2 + lambda ||W x||_1 + tv_lambda TV(x)
+where W is an orthogonal Haar wavelet and TV is a smoothed total variation
+*/
+#[derive(Debug,Deserialize,Serialize,Clone,PartialEq)]
+#[serde(default)]
+pub struct CsSettings{
+    pub max_iter:u32,
+    /* weight of the wavelet l1 term, comparable to the bart pics -r value */
+    pub lambda:f32,
+    /* weight of the total variation term. 0 leaves it out */
+    pub tv_lambda:f32,
+    pub wavelet_levels:usize,
+    /* combine coils with sensitivities estimated from the k-space center. Otherwise coils are reconstructed one at a time */
+    pub coil_weighting:bool,
+    pub calibration_size:usize,
+    /* give the image back on the scale of the data, like bart pics -S */
+    pub respect_scaling:bool,
+    /* stop when the relative change of the image falls below this */
+    pub tolerance:f32,
+    /* worker threads. All cores when left out */
+    pub threads:Option<usize>,
+}
+
+impl Default for CsSettings{
+    fn default() -> CsSettings{
+        return CsSettings{
+            max_iter:50,
+            lambda:0.005,
+            tv_lambda:0.0,
+            wavelet_levels:3,
+            coil_weighting:true,
+            calibration_size:24,
+            respect_scaling:true,
+            tolerance:1e-4,
+            threads:None,
+        }
+    }
+}
+
+// smoothing of the total variation term so that it has a gradient
+const TV_EPSILON:f32 = 0.01;
+
+/*
+cs_recon
+reconstructs a k-space cfl as written by Mrd::write_zero_filled_cfl ([readout,pe,pe,channels]) into img_cfl.
+Progress is written to log_file in the same "#It" form bart uses
+*/
+pub fn cs_recon(kspace_cfl:&str,img_cfl:&str,settings:&CsSettings,log_file:&Path) -> Convergence{
+    return match settings.threads{
+        Some(n) => {
+            let pool = rayon::ThreadPoolBuilder::new().num_threads(n).build().expect("cannot start thread pool");
+            pool.install(|| run_cs_recon(kspace_cfl,img_cfl,settings,log_file))
+        }
+        None => run_cs_recon(kspace_cfl,img_cfl,settings,log_file)
+    }
+}
+
+fn run_cs_recon(kspace_cfl:&str,img_cfl:&str,settings:&CsSettings,log_file:&Path) -> Convergence{
+    let mut dims = cfl::get_all_dims(Path::new(kspace_cfl));
+    while dims.len() < 4 {dims.push(1)}
+    if dims[4..].iter().any(|d| *d > 1){panic!("{} holds more than one volume {:?}",kspace_cfl,dims)}
+    let spatial = [dims[0],dims[1],dims[2]];
+    let n_coils = dims[3];
+    let n = spatial.iter().product::<usize>();
+
+    let raw = cfl::load(Path::new(kspace_cfl));
+    let kspace:Vec<Vec<Complex32>> = raw.chunks_exact(2*n).map(to_complex).collect();
+
+    let mask = sampled_lines(&kspace,spatial[0]);
+
+    let mut log = File::create(log_file).expect("cannot create log file");
+    writeln!(log,"native cs recon of {} {:?}",kspace_cfl,dims).expect("trouble writing to log");
+    writeln!(log,"{:?}",settings).expect("trouble writing to log");
+    let n_sampled = mask.iter().filter(|m| **m).count();
+    writeln!(log,"{} of {} phase encodes sampled",n_sampled,mask.len()).expect("trouble writing to log");
+
+    let mut iterations = Vec::<IterationRecord>::new();
+    let (image,out_dims) = match n_coils > 1 && settings.coil_weighting{
+        true => {
+            let sens = estimate_sensitivities(&kspace,&spatial,settings.calibration_size);
+            let problem = Problem{dims:spatial,mask:&mask,kspace:&kspace,sens:Some(sens)};
+            (problem.solve(settings,&mut log,&mut iterations),spatial.to_vec())
+        }
+        false => {
+            let mut image = Vec::<Complex32>::with_capacity(n*n_coils);
+            for (c,coil) in kspace.iter().enumerate(){
+                if n_coils > 1 {writeln!(log,"coil {}",c).expect("trouble writing to log");}
+                let problem = Problem{dims:spatial,mask:&mask,kspace:std::slice::from_ref(coil),sens:None};
+                image.extend(problem.solve(settings,&mut log,&mut iterations));
+            }
+            let mut out_dims = spatial.to_vec();
+            if n_coils > 1 {out_dims.push(n_coils)}
+            (image,out_dims)
+        }
+    };
+    cfl::write(Path::new(img_cfl),&to_interleaved(&image),&out_dims);
+    return Convergence{iterations:iterations};
+}
+
+/* a phase encode is sampled when any coil has data anywhere along the readout */
+pub(crate) fn sampled_lines(kspace:&[Vec<Complex32>],readout:usize) -> Vec<bool>{
+    return (0..kspace[0].len()/readout).into_par_iter().map(|pe|{
+        kspace.iter().any(|coil| coil[pe*readout..(pe+1)*readout].iter().any(|v| v.re != 0.0 || v.im != 0.0))
+    }).collect();
+}
+
+/* ||M F S x - y||^2 of an image against the sampled k-space. Without sensitivities there must be a single coil */
+pub(crate) fn residual_norm(kspace:&[Vec<Complex32>],sens:Option<Vec<Vec<Complex32>>>,dims:[usize;3],x:&[Complex32]) -> f64{
+    let mask = sampled_lines(kspace,dims[0]);
+    let problem = Problem{dims:dims,mask:&mask,kspace:kspace,sens:sens};
+    return problem.gradient(x,1.0).1;
+}
+
+/* l1 norm of the detail coefficients of the Haar wavelet used by the solver */
+pub(crate) fn wavelet_l1(x:&[Complex32],dims:[usize;3],levels:usize) -> f64{
+    let wavelet = Haar::new(dims,levels);
+    let mut coefs = x.to_vec();
+    wavelet.forward(&mut coefs);
+    return wavelet.l1_norm(&coefs);
+}
+
+/* sensitivities from a low resolution image of the k-space center, normalized by the root sum of squares */
+fn estimate_sensitivities(kspace:&[Vec<Complex32>],dims:&[usize;3],calibration_size:usize) -> Vec<Vec<Complex32>>{
+    let half = calibration_size/2;
+    let in_center = |i:usize| -> bool{
+        let coords = [i % dims[0],(i/dims[0]) % dims[1],i/(dims[0]*dims[1])];
+        return (0..3).all(|a| coords[a].abs_diff(dims[a]/2) <= half);
+    };
+    let mut low_res:Vec<Vec<Complex32>> = kspace.iter().map(|coil|{
+        let mut low:Vec<Complex32> = coil.par_iter().enumerate().map(|(i,v)| if in_center(i) {*v} else {Complex32::new(0.0,0.0)}).collect();
+        fft_axes(&mut low,dims,&[0,1,2],true);
+        low
+    }).collect();
+    let n = low_res[0].len();
+    let rss:Vec<f32> = (0..n).into_par_iter().map(|i| low_res.iter().map(|c| c[i].norm_sqr()).sum::<f32>().sqrt()).collect();
+    let floor = 1e-6*rss.iter().cloned().fold(0.0,f32::max);
+    low_res.iter_mut().for_each(|coil|{
+        coil.par_iter_mut().zip(rss.par_iter()).for_each(|(v,r)| *v /= r.max(floor));
+    });
+    return low_res;
+}
+
+struct Problem<'a>{
+    dims:[usize;3],
+    mask:&'a [bool],
+    kspace:&'a [Vec<Complex32>],
+    sens:Option<Vec<Vec<Complex32>>>,
+}
+
+impl<'a> Problem<'a>{
+
+    fn weight(&self,coil:usize,x:&[Complex32]) -> Vec<Complex32>{
+        return match &self.sens{
+            Some(s) => x.par_iter().zip(s[coil].par_iter()).map(|(a,b)| a*b).collect(),
+            None => x.to_vec(),
+        }
+    }
+
+    fn add_unweighted(&self,coil:usize,acc:&mut [Complex32],x:&[Complex32]){
+        match &self.sens{
+            Some(s) => acc.par_iter_mut().zip(x.par_iter().zip(s[coil].par_iter())).for_each(|(a,(v,w))| *a += v*w.conj()),
+            None => acc.par_iter_mut().zip(x.par_iter()).for_each(|(a,v)| *a += v),
+        }
+    }
+
+    /* zero-filled image, the adjoint of the encoding applied to the data */
+    fn adjoint(&self) -> Vec<Complex32>{
+        let n = self.kspace[0].len();
+        let mut acc = vec![Complex32::new(0.0,0.0);n];
+        for (c,coil) in self.kspace.iter().enumerate(){
+            let mut tmp = coil.clone();
+            fft_axes(&mut tmp,&self.dims,&[0,1,2],true);
+            self.add_unweighted(c,&mut acc,&tmp);
+        }
+        return acc;
+    }
+
+    /* gradient of the data term at x, and the squared norm of the residual */
+    fn gradient(&self,x:&[Complex32],scale:f32) -> (Vec<Complex32>,f64){
+        let mut grad = vec![Complex32::new(0.0,0.0);x.len()];
+        let mut residual = 0.0;
+        let readout = self.dims[0];
+        for (c,coil) in self.kspace.iter().enumerate(){
+            let mut tmp = self.weight(c,x);
+            fft_axes(&mut tmp,&self.dims,&[0,1,2],false);
+            residual += tmp.par_iter_mut().zip(coil.par_iter()).enumerate().map(|(i,(v,y))|{
+                match self.mask[i/readout]{
+                    true => {*v -= y*scale; v.norm_sqr() as f64}
+                    false => {*v = Complex32::new(0.0,0.0); 0.0}
+                }
+            }).sum::<f64>();
+            fft_axes(&mut tmp,&self.dims,&[0,1,2],true);
+            self.add_unweighted(c,&mut grad,&tmp);
+        }
+        return (grad,residual);
+    }
+
+    fn solve(&self,settings:&CsSettings,log:&mut File,iterations:&mut Vec<IterationRecord>) -> Vec<Complex32>{
+        let wavelet = Haar::new(self.dims,settings.wavelet_levels);
+
+        // the data is scaled so the bulk of the zero-filled image is near 1, which keeps lambda independent of the receiver gain
+        let mut x = self.adjoint();
+        let mut mags:Vec<f32> = x.par_iter().map(|v| v.norm()).collect();
+        mags.par_sort_by(|a,b| a.partial_cmp(b).unwrap());
+        let p90 = mags[(mags.len()*9)/10];
+        let scale = if p90 > 0.0 {1.0/p90} else {1.0};
+        x.par_iter_mut().for_each(|v| *v *= scale);
+        writeln!(log,"scaling: {}",scale).expect("trouble writing to log");
+
+        // lipschitz constant of the data term is 1 (unitary fft, normalized sensitivities)
+        let lipschitz = 1.0 + if settings.tv_lambda > 0.0 {12.0*settings.tv_lambda/TV_EPSILON} else {0.0};
+        let step = 1.0/lipschitz;
+        writeln!(log,"step size: {}",step).expect("trouble writing to log");
+
+        let mut z = x.clone();
+        let mut t:f32 = 1.0;
+        for it in 1..=settings.max_iter{
+            let (mut grad,residual) = self.gradient(&z,scale);
+            let mut objective = 0.5*residual;
+            if settings.tv_lambda > 0.0 {
+                let (tv_grad,tv) = total_variation(&z,&self.dims);
+                grad.par_iter_mut().zip(tv_grad.par_iter()).for_each(|(g,t)| *g += t*settings.tv_lambda);
+                objective += settings.tv_lambda as f64*tv;
+            }
+            let mut coefs = z.clone();
+            wavelet.forward(&mut coefs);
+            objective += settings.lambda as f64*wavelet.l1_norm(&coefs);
+
+            let mut x_new:Vec<Complex32> = z.par_iter().zip(grad.par_iter()).map(|(v,g)| v - g*step).collect();
+            wavelet.forward(&mut x_new);
+            wavelet.soft_threshold(&mut x_new,settings.lambda*step);
+            wavelet.inverse(&mut x_new);
+
+            let (diff,norm) = x_new.par_iter().zip(x.par_iter())
+                .map(|(a,b)| ((a-b).norm_sqr() as f64,a.norm_sqr() as f64))
+                .reduce(|| (0.0,0.0),|a,b| (a.0+b.0,a.1+b.1));
+            let change = if norm > 0.0 {(diff/norm).sqrt()} else {0.0};
+
+            let t_new = (1.0 + (1.0 + 4.0*t*t).sqrt())/2.0;
+            let momentum = (t - 1.0)/t_new;
+            z = x_new.par_iter().zip(x.par_iter()).map(|(a,b)| a + (a-b)*momentum).collect();
+            x = x_new;
+            t = t_new;
+
+            writeln!(log,"#It {:03}: {:.6e} {:e}",it,objective,step).expect("trouble writing to log");
+            iterations.push(IterationRecord{iteration:it as usize,objective:Some(objective),step_size:Some(step as f64)});
+            if change < settings.tolerance as f64 {
+                writeln!(log,"converged after {} iterations",it).expect("trouble writing to log");
+                break;
+            }
+        }
+        if settings.respect_scaling{
+            x.par_iter_mut().for_each(|v| *v /= scale);
+        }
+        return x;
+    }
+}
+
+/* smoothed total variation with forward differences, and its gradient */
+fn total_variation(x:&[Complex32],dims:&[usize;3]) -> (Vec<Complex32>,f64){
+    let strides = [1,dims[0],dims[0]*dims[1]];
+    let coords = |i:usize| [i % dims[0],(i/dims[0]) % dims[1],i/strides[2]];
+    let zero = Complex32::new(0.0,0.0);
+    let (normalized,tv):(Vec<[Complex32;3]>,Vec<f32>) = x.par_iter().enumerate().map(|(i,v)|{
+        let c = coords(i);
+        let mut d = [zero;3];
+        for a in 0..3{
+            if c[a] + 1 < dims[a] {d[a] = x[i + strides[a]] - v}
+        }
+        let w = (d.iter().map(|g| g.norm_sqr()).sum::<f32>() + TV_EPSILON*TV_EPSILON).sqrt();
+        ([d[0]/w,d[1]/w,d[2]/w],w)
+    }).unzip();
+    let grad:Vec<Complex32> = (0..x.len()).into_par_iter().map(|i|{
+        let c = coords(i);
+        let mut g = zero;
+        for a in 0..3{
+            if c[a] > 0 {g += normalized[i - strides[a]][a]}
+            g -= normalized[i][a];
+        }
+        g
+    }).collect();
+    return (grad,tv.par_iter().map(|w| *w as f64).sum());
+}
+
+/*
+Haar
+multi-level orthonormal Haar wavelet over the 3 spatial dimensions. Each level halves every axis that is
+still even, so odd or short axes stop being transformed. The coarsest approximation isn't thresholded
+*/
+struct Haar{
+    dims:[usize;3],
+    levels:Vec<([usize;3],Vec<usize>)>,
+    coarse:[usize;3],
+}
+
+impl Haar{
+    fn new(dims:[usize;3],n_levels:usize) -> Haar{
+        let mut levels = Vec::<([usize;3],Vec<usize>)>::new();
+        let mut region = dims;
+        for _ in 0..n_levels{
+            let axes:Vec<usize> = (0..3).filter(|a| region[*a] >= 2 && region[*a] % 2 == 0).collect();
+            if axes.is_empty() {break}
+            levels.push((region,axes.clone()));
+            axes.iter().for_each(|a| region[*a] /= 2);
+        }
+        return Haar{dims:dims,levels:levels,coarse:region};
+    }
+
+    fn forward(&self,x:&mut [Complex32]){
+        for (region,axes) in self.levels.iter(){
+            for a in axes.iter(){
+                for_each_line(x,&self.dims,region,*a,|line,scratch|{
+                    let half = line.len()/2;
+                    scratch.clear();
+                    scratch.extend_from_slice(line);
+                    for i in 0..half{
+                        line[i] = (scratch[2*i] + scratch[2*i+1])*std::f32::consts::FRAC_1_SQRT_2;
+                        line[half+i] = (scratch[2*i] - scratch[2*i+1])*std::f32::consts::FRAC_1_SQRT_2;
+                    }
+                });
+            }
+        }
+    }
+
+    fn inverse(&self,x:&mut [Complex32]){
+        for (region,axes) in self.levels.iter().rev(){
+            for a in axes.iter().rev(){
+                for_each_line(x,&self.dims,region,*a,|line,scratch|{
+                    let half = line.len()/2;
+                    scratch.clear();
+                    scratch.extend_from_slice(line);
+                    for i in 0..half{
+                        line[2*i] = (scratch[i] + scratch[half+i])*std::f32::consts::FRAC_1_SQRT_2;
+                        line[2*i+1] = (scratch[i] - scratch[half+i])*std::f32::consts::FRAC_1_SQRT_2;
+                    }
+                });
+            }
+        }
+    }
+
+    fn is_coarse(&self,i:usize) -> bool{
+        let c = [i % self.dims[0],(i/self.dims[0]) % self.dims[1],i/(self.dims[0]*self.dims[1])];
+        return c[0] < self.coarse[0] && c[1] < self.coarse[1] && c[2] < self.coarse[2];
+    }
+
+    fn soft_threshold(&self,coefs:&mut [Complex32],threshold:f32){
+        coefs.par_iter_mut().enumerate().for_each(|(i,v)|{
+            if self.is_coarse(i) {return}
+            let mag = v.norm();
+            *v = if mag > threshold {*v*((mag - threshold)/mag)} else {Complex32::new(0.0,0.0)};
+        });
+    }
+
+    fn l1_norm(&self,coefs:&[Complex32]) -> f64{
+        return coefs.par_iter().enumerate().filter(|(i,_)| !self.is_coarse(*i)).map(|(_,v)| v.norm() as f64).sum();
+    }
+}
+
+#[test]
+fn test_cs_recon(){
+    let dims = [16,16,8];
+    let n:usize = dims.iter().product();
+    // a box phantom
+    let truth:Vec<Complex32> = (0..n).map(|i|{
+        let c = [i % 16,(i/16) % 16,i/256];
+        let inside = (4..12).contains(&c[0]) && (5..11).contains(&c[1]) && (2..6).contains(&c[2]);
+        Complex32::new(if inside {1.0} else {0.0},0.0)
+    }).collect();
+
+    let wavelet = Haar::new(dims,3);
+    let mut w = truth.clone();
+    wavelet.forward(&mut w);
+    wavelet.inverse(&mut w);
+    w.iter().zip(truth.iter()).for_each(|(a,b)| assert!((a-b).norm() < 1e-5));
+
+    // keep every other phase encode line plus the center
+    let mut kspace = truth.clone();
+    fft_axes(&mut kspace,&dims,&[0,1,2],false);
+    kspace.iter_mut().enumerate().for_each(|(i,v)|{
+        let (p1,p2) = ((i/16) % 16,i/256);
+        let center = p1.abs_diff(8) <= 2 && p2.abs_diff(4) <= 1;
+        if !center && (p1 + p2) % 2 == 1 {*v = Complex32::new(0.0,0.0)}
+    });
+    let mut zero_filled = kspace.clone();
+    fft_axes(&mut zero_filled,&dims,&[0,1,2],true);
+
+    let dir = std::env::temp_dir().join("cs_solver_test");
+    std::fs::create_dir_all(&dir).expect("cannot make directory");
+    let kspace_cfl = dir.join("kspace");
+    let img_cfl = dir.join("img");
+    cfl::write(&kspace_cfl,&to_interleaved(&kspace),&dims);
+
+    let mut settings = CsSettings::default();
+    settings.max_iter = 100;
+    settings.lambda = 0.01;
+    settings.tv_lambda = 0.001;
+    let convergence = cs_recon(kspace_cfl.to_str().unwrap(),img_cfl.to_str().unwrap(),&settings,&dir.join("cs.log"));
+    assert!(!convergence.iterations.is_empty());
+
+    let img = to_complex(&cfl::load(&img_cfl));
+    let error = |x:&[Complex32]| x.iter().zip(truth.iter()).map(|(a,b)| (a-b).norm_sqr()).sum::<f32>().sqrt();
+    assert!(error(&img) < error(&zero_filled));
+}
@@ -0,0 +1,210 @@
+use rayon::prelude::*;
+use rustfft::FftPlanner;
+use rustfft::num_complex::Complex32;
+use std::path::Path;
+use crate::cfl;
+
+/*
+Line-wise operations over complex arrays stored in cfl order (dimension 0 fastest).
+Lines along an axis are gathered into a contiguous buffer, worked on in parallel, then scattered back
+in parallel over the slowest dimension
+*/
+
+fn strides(dims:&[usize]) -> Vec<usize>{
+    let mut s = vec![1;dims.len()];
+    for i in 1..dims.len(){
+        s[i] = s[i-1]*dims[i-1];
+    }
+    return s;
+}
+
+/*
+for_each_line
+calls f on every line along axis inside region (a box starting at the origin, region[i] <= dims[i]).
+f gets a scratch vector it may resize, reused between lines on the same thread
+*/
+pub(crate) fn for_each_line<F>(data:&mut [Complex32],dims:&[usize],region:&[usize],axis:usize,f:F)
+where F: Fn(&mut [Complex32],&mut Vec<Complex32>) + Sync
+{
+    let n = region[axis];
+    if n == 0 {return}
+    let stride = strides(dims);
+    let others:Vec<usize> = (0..dims.len()).filter(|a| *a != axis).collect();
+    let n_lines:usize = others.iter().map(|a| region[*a]).product();
+    let mut buf = vec![Complex32::new(0.0,0.0);n_lines*n];
+
+    let src:&[Complex32] = data;
+    buf.par_chunks_mut(n).enumerate().for_each_init(Vec::<Complex32>::new,|scratch,(l,line)|{
+        let mut rem = l;
+        let mut base = 0;
+        for a in others.iter(){
+            base += (rem % region[*a])*stride[*a];
+            rem /= region[*a];
+        }
+        for (k,v) in line.iter_mut().enumerate(){
+            *v = src[base + k*stride[axis]];
+        }
+        f(line,scratch);
+    });
+
+    let last = dims.len() - 1;
+    let inner_region:usize = region[0..last].iter().product();
+    data.par_chunks_mut(stride[last]).take(region[last]).enumerate().for_each(|(k,slab)|{
+        let mut coords = vec![0;dims.len()];
+        coords[last] = k;
+        for i in 0..inner_region{
+            let mut rem = i;
+            let mut offset = 0;
+            for a in 0..last{
+                coords[a] = rem % region[a];
+                rem /= region[a];
+                offset += coords[a]*stride[a];
+            }
+            let mut line = 0;
+            let mut radix = 1;
+            for a in others.iter(){
+                line += coords[*a]*radix;
+                radix *= region[*a];
+            }
+            slab[offset] = buf[line*n + coords[axis]];
+        }
+    });
+}
+
+/*
+centered, unitary fft over the listed axes of an N-D array. The origin of k-space and of the image is at n/2
+along each axis, matching bart fft -u
+*/
+pub fn fft_axes(data:&mut [Complex32],dims:&[usize],axes:&[usize],inverse:bool){
+    let mut planner = FftPlanner::<f32>::new();
+    for axis in axes.iter(){
+        let n = dims[*axis];
+        if n < 2 {continue}
+        let plan = match inverse{
+            true => planner.plan_fft_inverse(n),
+            false => planner.plan_fft_forward(n),
+        };
+        let scale = 1.0/(n as f32).sqrt();
+        for_each_line(data,dims,dims,*axis,|line,scratch|{
+            scratch.resize(plan.get_inplace_scratch_len(),Complex32::new(0.0,0.0));
+            line.rotate_left(n/2);
+            plan.process_with_scratch(line,scratch);
+            line.rotate_right(n/2);
+            line.iter_mut().for_each(|v| *v *= scale);
+        });
+    }
+}
+
+/*
+thickness readout positions starting at x0 of hybrid data ([readout,...] with the readout already in image space),
+transformed back to k-space along the readout so it can be reconstructed like any cartesian volume
+*/
+pub fn readout_slab(hybrid:&[Complex32],dims:&[usize],x0:usize,thickness:usize) -> Vec<Complex32>{
+    let readout = dims[0];
+    let lines = hybrid.len()/readout;
+    let mut slab = vec![Complex32::new(0.0,0.0);thickness*lines];
+    slab.par_chunks_mut(thickness).enumerate().for_each(|(line,s)|{
+        s.copy_from_slice(&hybrid[line*readout + x0..line*readout + x0 + thickness]);
+    });
+    let mut slab_dims = dims.to_vec();
+    slab_dims[0] = thickness;
+    fft_axes(&mut slab,&slab_dims,&[0],false);
+    return slab;
+}
+
+pub fn to_complex(floats:&[f32]) -> Vec<Complex32>{
+    return floats.chunks_exact(2).map(|c| Complex32::new(c[0],c[1])).collect();
+}
+
+pub fn to_interleaved(data:&[Complex32]) -> Vec<f32>{
+    return data.iter().flat_map(|v| [v.re,v.im]).collect();
+}
+
+/* fft of a cfl over the listed axes, written to another cfl with the same dimensions */
+pub fn fft_cfl(input:&Path,output:&Path,axes:&[usize],inverse:bool){
+    let dims = cfl::get_all_dims(input);
+    if let Some(a) = axes.iter().find(|a| **a >= dims.len()){
+        panic!("cannot transform axis {} of {:?} with dimensions {:?}",a,input,dims);
+    }
+    let mut data = to_complex(&cfl::load(input));
+    println!("{} fft over axes {:?} of {:?} ...",if inverse {"inverse"} else {"forward"},axes,dims);
+    fft_axes(&mut data,&dims,axes,inverse);
+    cfl::write(output,&to_interleaved(&data),&dims);
+}
+
+/*
+image of zero-filled or fully sampled k-space ([readout,pe,pe,channels]) by an inverse fft over the spatial
+dimensions. Channels stay separate and are combined when the image is written
+*/
+pub fn fft_recon(kspace_cfl:&Path,img_cfl:&Path){
+    fft_cfl(kspace_cfl,img_cfl,&[0,1,2],true);
+}
+
+#[test]
+fn test_fft(){
+    let dims = [6,4,5];
+    let n:usize = dims.iter().product();
+    let data:Vec<Complex32> = (0..n).map(|i| Complex32::new((i % 7) as f32,(i % 3) as f32 - 1.0)).collect();
+
+    // round trip
+    let mut x = data.clone();
+    fft_axes(&mut x,&dims,&[0,1,2],false);
+    fft_axes(&mut x,&dims,&[0,1,2],true);
+    x.iter().zip(data.iter()).for_each(|(a,b)| assert!((a-b).norm() < 1e-4));
+
+    // a point at the center of k-space is a flat image
+    let mut k = vec![Complex32::new(0.0,0.0);n];
+    k[3 + 6*(2 + 4*2)] = Complex32::new(1.0,0.0);
+    fft_axes(&mut k,&dims,&[0,1,2],true);
+    let expected = 1.0/(n as f32).sqrt();
+    k.iter().for_each(|v| assert!((v.re - expected).abs() < 1e-6 && v.im.abs() < 1e-6));
+}
+
+#[test]
+fn test_petable_placement(){
+    use crate::mrd::{Mrd,MrdWriter};
+    use crate::pe_table::Petable;
+    use std::io::Write;
+    let dims = [8,8,8];
+    let n:usize = dims.iter().product();
+    // a single bright voxel away from the center
+    let bright = 2 + 8*(5 + 8*1);
+    let mut truth = vec![Complex32::new(0.0,0.0);n];
+    truth[bright] = Complex32::new(1.0,0.0);
+    let mut kspace = truth.clone();
+    fft_axes(&mut kspace,&dims,&[0,1,2],false);
+    let dir = std::env::temp_dir();
+    let peak = |cfl:&Path| -> usize{
+        let img = to_complex(&cfl::load(cfl));
+        return (0..img.len()).max_by(|a,b| img[*a].norm().partial_cmp(&img[*b].norm()).unwrap()).unwrap();
+    };
+
+    // fully sampled
+    let mrd_path = dir.join("cs_reco_test_cartesian.mrd");
+    MrdWriter::new([8,8,8,1,1,1]).write_complex_f32(mrd_path.to_str().unwrap(),&to_interleaved(&kspace));
+    let mrd = Mrd::new(mrd_path.to_str().unwrap());
+    let kspace_cfl = dir.join("cs_reco_test_cartesian_kspace");
+    let img_cfl = dir.join("cs_reco_test_cartesian_img");
+    mrd.write_cartesian_volume_cfl(0,kspace_cfl.to_str().unwrap());
+    fft_recon(&kspace_cfl,&img_cfl);
+    let img = to_complex(&cfl::load(&img_cfl));
+    img.iter().zip(truth.iter()).for_each(|(a,b)| assert!((a-b).norm() < 1e-5));
+
+    // an irregular subset of the phase encodes with the center, acquired in table order
+    let ptab = dir.join("stream_CS8_2x_placement_test");
+    let coords:Vec<(i32,i32)> = (-4..4).flat_map(|a| (-4..4).map(move |b| (a,b)))
+        .filter(|(a,b):&(i32,i32)| (a.abs() <= 1 && b.abs() <= 1) || (7*a + 3*b*b + 40) % 5 < 3).rev().collect();
+    let table:String = coords.iter().map(|(a,b)| format!("{}\r\n{}\r\n",a,b)).collect();
+    std::fs::File::create(&ptab).unwrap().write_all(table.as_bytes()).unwrap();
+    let petab = Petable::new(ptab.to_str().unwrap()).unwrap();
+    let views:Vec<Complex32> = petab.indices().iter().flat_map(|(slice,view)|{
+        let start = 8*(view + 8*slice);
+        kspace[start..start+8].to_vec()
+    }).collect();
+    let cs_path = dir.join("cs_reco_test_placement.mrd");
+    MrdWriter::new([8,coords.len() as i32,1,1,1,1]).write_complex_f32(cs_path.to_str().unwrap(),&to_interleaved(&views));
+    let mrd = Mrd::new(cs_path.to_str().unwrap());
+    mrd.write_zero_filled_volume_cfl(0,kspace_cfl.to_str().unwrap(),&petab,false);
+    fft_recon(&kspace_cfl,&img_cfl);
+    assert_eq!(peak(&img_cfl),bright);
+}
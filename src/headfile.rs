@@ -2,6 +2,7 @@ use std::collections::HashMap;
 use std::fs::File;
 use std::io::{Read, Write};
 use std::path::{Path,PathBuf};
+use crate::mrd_params::MrdParameters;
 
 pub struct Headfile{
     items:HashMap<String,String>
@@ -15,6 +16,13 @@ impl Headfile{
         return Headfile{items:Headfile::txt_to_hash(strbuff)}
     }
 
+    /* seed a headfile from the parameter trailer of the mrd when the _meta.txt file isn't available */
+    pub fn from_mrd_parameters(params:&MrdParameters) -> Headfile{
+        let mut items = params.to_hash();
+        Headfile::translate_field_names(&mut items);
+        return Headfile{items:items}
+    }
+
     pub fn append_field<T,U>(&mut self,key:T,value:U)
     where T:std::string::ToString, U:std::string::ToString
     {
@@ -1,5 +1,7 @@
 pub mod mrd;
+pub mod mrd_params;
 pub mod pe_table;
+pub mod pe_table_gen;
 pub mod config_;
 pub mod headfile;
 pub mod volume_index;
@@ -8,6 +10,14 @@ pub mod slurm;
 mod utils;
 pub mod cfl;
 pub mod bart_wrapper;
+pub mod recon_engine;
+pub mod slab_recon;
+pub mod metrics;
+pub mod sweep;
+pub mod auto_lambda;
+pub mod simulate;
+pub mod cs_solver;
+pub mod fft;
 pub mod volume_manager;
 pub mod test;
 pub mod config;
\ No newline at end of file
@@ -4,6 +4,15 @@
 */
 use cs_reco::volume_manager::{launch_volume_manager,re_launch_volume_manager};
 use cs_reco::test::{main_test_cluster};
+use cs_reco::mrd::{Mrd,MrdValidation};
+use cs_reco::pe_table::{Petable,PetableSettings};
+use cs_reco::pe_table_gen::PoissonDiscSettings;
+use cs_reco::fft::fft_recon;
+use cs_reco::config::ProjectSettings;
+use cs_reco::sweep::{SweepSettings,run_sweep};
+use cs_reco::auto_lambda::{AutoLambdaSettings,propose_lambda};
+use cs_reco::simulate::simulate;
+use std::time::Duration;
 use clap::Parser;
 use std::path::Path;
 
@@ -27,7 +36,8 @@ struct VolumeManagerArgs{
     working_directory:String,
     mrd_file:String,
     phase_encode_stream_table:String,
-    volume_offset:usize,
+    /// volume offset ("4") or address ("echo=3,experiment=2,average=0")
+    volume:String,
     reco_settings_json:String
 }
 
@@ -38,6 +48,157 @@ struct VolumeManagerRelaunchArgs{
     working_directory:String,
 }
 
+/*
+    List volumes args
+*/
+#[derive(Parser, Debug)]
+#[clap(author, version, about, long_about = None)]
+struct ListVolumesArgs{
+    parent:String,
+    mrd_file:String,
+}
+
+/*
+    Check mrd args
+*/
+#[derive(Parser, Debug)]
+#[clap(author, version, about, long_about = None)]
+struct CheckMrdArgs{
+    parent:String,
+    mrd_file:String,
+    /// seconds to watch the file for growth
+    #[clap(default_value_t = 2)]
+    settle_seconds:u64,
+}
+
+/*
+    Check petable args: does a stream table fit the data
+*/
+#[derive(Parser, Debug)]
+#[clap(author, version, about, long_about = None)]
+struct CheckPetableArgs{
+    parent:String,
+    mrd_file:String,
+    phase_encode_stream_table:String,
+    /// writes <output>_mask and <output>_density cfls when given
+    output:Option<String>,
+}
+
+/*
+    Make petable args: variable density poisson-disc stream table
+*/
+#[derive(Parser, Debug)]
+#[clap(author, version, about, long_about = None)]
+struct MakePetableArgs{
+    parent:String,
+    /// phase encodes along ky. kz is the same unless --size-kz is given
+    size:usize,
+    acceleration:u32,
+    /// relative minimum spacing of views at the center of k-space
+    pa:u32,
+    /// relative minimum spacing of views at the edge of k-space
+    pb:u32,
+    /// directory the table is written to under its stream_CS name
+    output_directory:String,
+    #[clap(default_value_t = 0)]
+    seed:u64,
+    /// random, center-out or segmented:n
+    #[clap(default_value = "random")]
+    ordering:String,
+    /// radius of the fully sampled center in samples
+    center_radius:Option<f64>,
+    /// phase encodes along kz when it differs from ky
+    size_kz:Option<usize>,
+}
+
+/*
+    Preview args: zero-filled recon of one volume of compressed data
+*/
+#[derive(Parser, Debug)]
+#[clap(author, version, about, long_about = None)]
+struct PreviewArgs{
+    parent:String,
+    mrd_file:String,
+    phase_encode_stream_table:String,
+    /// volume offset ("4") or address ("echo=3,experiment=2,average=0")
+    volume:String,
+    /// image cfl to write. The k-space is written next to it as <output>_kspace
+    output:String,
+}
+
+/*
+    Fft recon args: fully sampled cartesian data
+*/
+#[derive(Parser, Debug)]
+#[clap(author, version, about, long_about = None)]
+struct FftReconArgs{
+    parent:String,
+    mrd_file:String,
+    /// volume offset ("4") or address ("echo=3,experiment=2,average=0")
+    volume:String,
+    /// image cfl to write. The k-space is written next to it as <output>_kspace
+    output:String,
+}
+
+/*
+    Sweep args: reconstruct one volume over a grid of pics settings
+*/
+#[derive(Parser, Debug)]
+#[clap(author, version, about, long_about = None)]
+struct SweepArgs{
+    parent:String,
+    /// project settings toml the other pics settings come from
+    project:String,
+    mrd_file:String,
+    phase_encode_stream_table:String,
+    /// volume offset ("4") or address ("echo=3,experiment=2,average=0")
+    volume:String,
+    /// sweep grid toml. A template is written when it doesn't exist
+    sweep_settings:String,
+    output_directory:String,
+}
+
+/*
+    Auto lambda args: propose a regularization weight for a project
+*/
+#[derive(Parser, Debug)]
+#[clap(author, version, about, long_about = None)]
+struct AutoLambdaArgs{
+    parent:String,
+    /// project settings toml. The proposal is written next to the results as <label>_proposal.toml
+    project:String,
+    mrd_file:String,
+    phase_encode_stream_table:String,
+    /// volume offset ("4") or address ("echo=3,experiment=2,average=0")
+    volume:String,
+    output_directory:String,
+    /// l-curve or discrepancy
+    #[clap(default_value = "l-curve")]
+    selection:String,
+    /// central readout positions to reconstruct
+    #[clap(default_value_t = 32)]
+    crop:usize,
+}
+
+/*
+    Simulate args: retrospectively undersample a fully sampled volume with candidate tables
+*/
+#[derive(Parser, Debug)]
+#[clap(author, version, about, long_about = None)]
+struct SimulateArgs{
+    parent:String,
+    /// project settings toml. Its engine reconstructs the undersampled data
+    project:String,
+    /// fully sampled cartesian mrd
+    mrd_file:String,
+    /// volume offset ("4") or address ("echo=3,experiment=2,average=0")
+    volume:String,
+    output_directory:String,
+    /// one or more phase encode stream tables to compare
+    #[clap(required = true)]
+    phase_encode_stream_tables:Vec<String>,
+}
+
 /*
     Mrd to cfl args
 */
@@ -49,23 +210,127 @@ struct MrdToCflArgs{
     cmd3:String
 }
 
+/* a table that can't be read ends the command with its reason */
+fn open_petable(path:&str,settings:&PetableSettings) -> Petable{
+    return match Petable::open(path,settings){
+        Ok(petab) => petab,
+        Err(e) => {
+            println!("{}",e);
+            std::process::exit(1);
+        }
+    }
+}
+
 fn main(){
     let args = Args::parse();
     match args.sub_cmd.as_str(){
         "volume-manager" => {
             let a = VolumeManagerArgs::parse();
+            let volume_offset = Mrd::new(&a.mrd_file).resolve_volume(&a.volume);
             launch_volume_manager(
                 &a.working_directory,
                 &a.mrd_file,
                 &a.phase_encode_stream_table,
-                a.volume_offset,
+                volume_offset,
                 &Path::new(&a.reco_settings_json)
             );
         }
+        "list-volumes" => {
+            let a = ListVolumesArgs::parse();
+            let mrd = Mrd::new(&a.mrd_file);
+            println!("{}",mrd);
+            mrd.addresses().iter().enumerate().for_each(|(i,addr)| println!("{}\t{}",i,addr));
+        }
         "volume-manager-relaunch" => {
             let a = VolumeManagerRelaunchArgs::parse();
             re_launch_volume_manager(&a.working_directory);
         },
+        "check-mrd" => {
+            let a = CheckMrdArgs::parse();
+            let v = MrdValidation::new(&a.mrd_file,Duration::from_secs(a.settle_seconds));
+            println!("{}",v);
+            if !v.is_complete(){std::process::exit(1)}
+        }
+        "check-petable" => {
+            let a = CheckPetableArgs::parse();
+            let petab = open_petable(&a.phase_encode_stream_table,&PetableSettings::default());
+            let v = petab.validate(&Mrd::new(&a.mrd_file));
+            println!("{}",v);
+            if let Some(output) = a.output{
+                petab.write_mask_cfl(Path::new(&format!("{}_mask",output)));
+                petab.write_density_cfl(Path::new(&format!("{}_density",output)));
+            }
+            if !v.is_valid(){std::process::exit(1)}
+        }
+        "make-petable" => {
+            let a = MakePetableArgs::parse();
+            let mut settings = PoissonDiscSettings::new(a.size,a.acceleration,a.pa,a.pb);
+            if let Some(kz) = a.size_kz {settings.size_kz = kz}
+            if let Some(r) = a.center_radius {settings.center_radius = r}
+            settings.seed = a.seed;
+            settings.ordering = a.ordering.parse().unwrap_or_else(|e| panic!("{}",e));
+            std::fs::create_dir_all(&a.output_directory).expect("cannot make output directory");
+            let path = settings.write_table(Path::new(&a.output_directory));
+            println!("wrote {:?}",path);
+        }
+        "preview" => {
+            let a = PreviewArgs::parse();
+            let mrd = Mrd::new(&a.mrd_file);
+            let petab = open_petable(&a.phase_encode_stream_table,&PetableSettings::default());
+            let kspace = format!("{}_kspace",a.output);
+            // whatever has been acquired so far is used
+            mrd.write_zero_filled_volume_cfl(mrd.resolve_volume(&a.volume),&kspace,&petab,true);
+            fft_recon(Path::new(&kspace),Path::new(&a.output));
+        }
+        "fft-recon" => {
+            let a = FftReconArgs::parse();
+            let mrd = Mrd::new(&a.mrd_file);
+            let kspace = format!("{}_kspace",a.output);
+            mrd.write_cartesian_volume_cfl(mrd.resolve_volume(&a.volume),&kspace);
+            fft_recon(Path::new(&kspace),Path::new(&a.output));
+        }
+        "sweep" => {
+            let a = SweepArgs::parse();
+            let project = ProjectSettings::open(&a.project);
+            let sweep = SweepSettings::open(&a.sweep_settings);
+            project.validate().unwrap_or_else(|e| panic!("{}",e));
+            let mrd = project.open_mrd(&a.mrd_file);
+            let petab = open_petable(&a.phase_encode_stream_table,&project.pe_table);
+            let outdir = Path::new(&a.output_directory);
+            std::fs::create_dir_all(outdir).expect("cannot make sweep directory");
+            let kspace = outdir.join("kspace");
+            mrd.write_zero_filled_volume_cfl(mrd.resolve_volume(&a.volume),kspace.to_str().unwrap(),&petab,false);
+            let results = run_sweep(kspace.to_str().unwrap(),outdir,&project.recon_settings,&sweep);
+            println!("{} reconstructions summarized in {:?}",results.len(),outdir.join("sweep_summary.tsv"));
+        }
+        "auto-lambda" => {
+            let a = AutoLambdaArgs::parse();
+            let mut project = ProjectSettings::open(&a.project);
+            let mut settings = AutoLambdaSettings::default();
+            settings.selection = a.selection.parse().unwrap_or_else(|e| panic!("{}",e));
+            settings.crop = a.crop;
+            project.validate().unwrap_or_else(|e| panic!("{}",e));
+            let mrd = project.open_mrd(&a.mrd_file);
+            let petab = open_petable(&a.phase_encode_stream_table,&project.pe_table);
+            let outdir = Path::new(&a.output_directory);
+            std::fs::create_dir_all(outdir).expect("cannot make output directory");
+            let kspace = outdir.join("kspace");
+            mrd.write_zero_filled_volume_cfl(mrd.resolve_volume(&a.volume),kspace.to_str().unwrap(),&petab,false);
+            let proposal = propose_lambda(kspace.to_str().unwrap(),outdir,&project.recon_settings,&settings);
+            project.recon_settings.regularizers.clear();
+            project.recon_settings.set_regularization(&proposal.algorithm,proposal.lambda);
+            let proposal_file = outdir.join(format!("{}_proposal",project.label));
+            proposal.to_project_file(&project,proposal_file.to_str().unwrap());
+            println!("proposed lambda {} ({:?}) written to {:?}",proposal.lambda,proposal.selection,proposal_file.with_extension("toml"));
+        }
+        "simulate" => {
+            let a = SimulateArgs::parse();
+            let project = ProjectSettings::open(&a.project);
+            let mrd = project.open_mrd(&a.mrd_file);
+            let outdir = Path::new(&a.output_directory);
+            let reports = simulate(&mrd,mrd.resolve_volume(&a.volume),&a.phase_encode_stream_tables,&project,outdir);
+            println!("{} tables compared in {:?}",reports.len(),outdir.join("simulation_summary.tsv"));
+        }
         "cluster-test" => {
             main_test_cluster();
         },
@@ -0,0 +1,177 @@
+use serde::{Deserialize, Serialize};
+
+/*
+ImageMetrics
+quality numbers for a magnitude volume ([x,y,z] with x fastest) that don't need a reference image.
+Noise is taken from the 8 corner blocks of the volume, which are assumed to hold no tissue
+    snr: mean of the brightest 10% of voxels over the standard deviation of the corners
+    sharpness: mean gradient magnitude over the mean intensity
+*/
+#[derive(Debug,Clone,PartialEq,Serialize,Deserialize)]
+pub struct ImageMetrics{
+    pub mean:f64,
+    pub max:f64,
+    pub noise_std:f64,
+    pub snr:f64,
+    pub sharpness:f64,
+}
+
+impl ImageMetrics{
+    pub fn new(mag:&[f32],dims:[usize;3]) -> ImageMetrics{
+        let n = mag.len();
+        if n != dims.iter().product::<usize>() {panic!("{} voxels cannot fit into {:?}",n,dims)}
+        let mean = mag.iter().map(|v| *v as f64).sum::<f64>()/n as f64;
+        let max = mag.iter().cloned().fold(0.0,f32::max) as f64;
+
+        let mut sorted = mag.to_vec();
+        sorted.sort_by(|a,b| a.partial_cmp(b).unwrap());
+        let bright = &sorted[(9*n)/10..];
+        let signal = bright.iter().map(|v| *v as f64).sum::<f64>()/bright.len().max(1) as f64;
+
+        let corners = corner_voxels(mag,dims);
+        let corner_mean = corners.iter().sum::<f64>()/corners.len() as f64;
+        let noise_std = (corners.iter().map(|v| (v - corner_mean).powi(2)).sum::<f64>()/corners.len() as f64).sqrt();
+
+        let idx = |x:usize,y:usize,z:usize| x + dims[0]*(y + dims[1]*z);
+        let mut gradient = 0.0;
+        for z in 0..dims[2]{
+            for y in 0..dims[1]{
+                for x in 0..dims[0]{
+                    let v = mag[idx(x,y,z)] as f64;
+                    let gx = if x + 1 < dims[0] {mag[idx(x+1,y,z)] as f64 - v} else {0.0};
+                    let gy = if y + 1 < dims[1] {mag[idx(x,y+1,z)] as f64 - v} else {0.0};
+                    let gz = if z + 1 < dims[2] {mag[idx(x,y,z+1)] as f64 - v} else {0.0};
+                    gradient += (gx*gx + gy*gy + gz*gz).sqrt();
+                }
+            }
+        }
+        let sharpness = if mean > 0.0 {gradient/(n as f64*mean)} else {0.0};
+        let snr = if noise_std > 0.0 {signal/noise_std} else {f64::INFINITY};
+        return ImageMetrics{mean:mean,max:max,noise_std:noise_std,snr:snr,sharpness:sharpness};
+    }
+}
+
+/*
+ReferenceMetrics
+agreement of a magnitude volume with a reference of the same size. Intensities are taken relative to
+the reference maximum
+    nrmse: ||x - ref|| / ||ref||
+    psnr: 20 log10(max(ref)/rmse) in dB
+    ssim: mean structural similarity over 7x7x7 windows
+*/
+#[derive(Debug,Clone,PartialEq,Serialize,Deserialize)]
+pub struct ReferenceMetrics{
+    pub nrmse:f64,
+    pub psnr:f64,
+    pub ssim:f64,
+}
+
+impl ReferenceMetrics{
+    pub fn new(x:&[f32],reference:&[f32],dims:[usize;3]) -> ReferenceMetrics{
+        if x.len() != reference.len() {panic!("image has {} voxels but the reference has {}",x.len(),reference.len())}
+        let err = x.iter().zip(reference.iter()).map(|(a,b)| ((a - b) as f64).powi(2)).sum::<f64>();
+        let ref_norm = reference.iter().map(|v| (*v as f64).powi(2)).sum::<f64>();
+        let peak = reference.iter().cloned().fold(0.0,f32::max) as f64;
+        let rmse = (err/x.len() as f64).sqrt();
+        let map = ssim_map(x,reference,dims);
+        return ReferenceMetrics{
+            nrmse:(err/ref_norm).sqrt(),
+            psnr:20.0*(peak/rmse).log10(),
+            ssim:map.iter().map(|v| *v as f64).sum::<f64>()/map.len() as f64,
+        };
+    }
+}
+
+const SSIM_RADIUS:usize = 3;
+
+/* local structural similarity of x to the reference at every voxel */
+pub fn ssim_map(x:&[f32],reference:&[f32],dims:[usize;3]) -> Vec<f32>{
+    let peak = reference.iter().cloned().fold(0.0,f32::max).max(f32::MIN_POSITIVE) as f64;
+    let a:Vec<f64> = x.iter().map(|v| *v as f64/peak).collect();
+    let b:Vec<f64> = reference.iter().map(|v| *v as f64/peak).collect();
+    let mean_a = box_filter(&a,dims);
+    let mean_b = box_filter(&b,dims);
+    let aa = box_filter(&a.iter().map(|v| v*v).collect::<Vec<f64>>(),dims);
+    let bb = box_filter(&b.iter().map(|v| v*v).collect::<Vec<f64>>(),dims);
+    let ab = box_filter(&a.iter().zip(b.iter()).map(|(u,v)| u*v).collect::<Vec<f64>>(),dims);
+    let (c1,c2) = (0.01f64.powi(2),0.03f64.powi(2));
+    return (0..a.len()).map(|i|{
+        let (ma,mb) = (mean_a[i],mean_b[i]);
+        let var_a = aa[i] - ma*ma;
+        let var_b = bb[i] - mb*mb;
+        let cov = ab[i] - ma*mb;
+        (((2.0*ma*mb + c1)*(2.0*cov + c2))/((ma*ma + mb*mb + c1)*(var_a + var_b + c2))) as f32
+    }).collect();
+}
+
+/* mean over a cube of side 2*SSIM_RADIUS+1, clipped at the edges. Separable, one axis at a time */
+fn box_filter(x:&[f64],dims:[usize;3]) -> Vec<f64>{
+    let strides = [1,dims[0],dims[0]*dims[1]];
+    let mut out = x.to_vec();
+    for axis in 0..3{
+        let n = dims[axis];
+        let src = out.clone();
+        let mut line = vec![0.0;n];
+        let mut prefix = vec![0.0;n+1];
+        for start in 0..x.len(){
+            // visit each line once, from its first voxel
+            if (start/strides[axis]) % n != 0 {continue}
+            for k in 0..n {line[k] = src[start + k*strides[axis]]}
+            for k in 0..n {prefix[k+1] = prefix[k] + line[k]}
+            for k in 0..n{
+                let lo = k.saturating_sub(SSIM_RADIUS);
+                let hi = (k + SSIM_RADIUS + 1).min(n);
+                out[start + k*strides[axis]] = (prefix[hi] - prefix[lo])/(hi - lo) as f64;
+            }
+        }
+    }
+    return out;
+}
+
+/* voxels in the corner blocks, an eighth of each dimension on a side */
+fn corner_voxels(mag:&[f32],dims:[usize;3]) -> Vec<f64>{
+    let edge = |d:usize| (d/8).max(1);
+    let near_edge = |c:usize,d:usize| c < edge(d) || c >= d - edge(d);
+    let mut v = Vec::<f64>::new();
+    for z in 0..dims[2]{
+        for y in 0..dims[1]{
+            for x in 0..dims[0]{
+                if near_edge(x,dims[0]) && near_edge(y,dims[1]) && near_edge(z,dims[2]){
+                    v.push(mag[x + dims[0]*(y + dims[1]*z)] as f64);
+                }
+            }
+        }
+    }
+    return v;
+}
+
+#[test]
+fn test_image_metrics(){
+    let dims = [16,16,16];
+    // a bright box on a slightly noisy background
+    let mag:Vec<f32> = (0..4096).map(|i|{
+        let (x,y,z) = (i % 16,(i/16) % 16,i/256);
+        let inside = (4..12).contains(&x) && (4..12).contains(&y) && (4..12).contains(&z);
+        if inside {100.0} else {(i % 3) as f32}
+    }).collect();
+    let m = ImageMetrics::new(&mag,dims);
+    assert_eq!(m.max,100.0);
+    assert!(m.snr > 50.0);
+    let quieter:Vec<f32> = mag.iter().map(|v| v.max(1.0)).collect();
+    assert!(ImageMetrics::new(&quieter,dims).snr > m.snr);
+    assert!(m.sharpness > 0.0);
+}
+
+#[test]
+fn test_reference_metrics(){
+    let dims = [12,10,8];
+    let reference:Vec<f32> = (0..960).map(|i| ((i % 12) + (i/12) % 10) as f32).collect();
+    let m = ReferenceMetrics::new(&reference,&reference,dims);
+    assert_eq!(m.nrmse,0.0);
+    assert!((m.ssim - 1.0).abs() < 1e-9 && m.psnr.is_infinite());
+    let noisy:Vec<f32> = reference.iter().enumerate().map(|(i,v)| v + if i % 2 == 0 {1.0} else {-1.0}).collect();
+    let m = ReferenceMetrics::new(&noisy,&reference,dims);
+    assert!(m.nrmse > 0.0 && m.ssim < 1.0);
+    // rmse of 1 against a peak of 20
+    assert!((m.psnr - 20.0*20f64.log10()).abs() < 1e-9);
+}
@@ -1,19 +1,24 @@
-use ndarray::{s,Array3,Array4,Order};
 use std::fs::File;
-use std::io::{BufReader, Read, Seek, SeekFrom, Write};
+use std::io::Write;
 use byteorder::{ByteOrder, LittleEndian};
+use memmap2::Mmap;
 use std::path::Path;
 use core::ops::Range;
 use std::fmt;
-use std::mem::size_of;
+use std::str::FromStr;
+use std::time::Duration;
+use serde::{Deserialize, Serialize};
 use crate::utils;
-use crate::pe_table::Petable;
+use crate::cfl;
+use crate::pe_table::{Petable,PetableSettings};
+use crate::bart_wrapper::weights_cfl;
+use crate::mrd_params::MrdParameters;
 
 /*
 mrd_to_cfl
 attempts to write a complex floating point format (bart format) for use with their tools
 it requires an ASCII table that determines where lines of kspace get placed
-it also requires a table size and assumes the table is square for now :)
+it also requires a table size, used when the table doesn't state its own (square)
 */
 pub fn mrd_to_cfl(mrd:&str,vol_index:&str,petable:&str,table_size:&str,cfl:&str){
     let vidx:u16 = vol_index.parse().expect("cannot parse mrd volume offset. check the input");
@@ -21,7 +26,8 @@ pub fn mrd_to_cfl(mrd:&str,vol_index:&str,petable:&str,table_size:&str,cfl:&str)
     let mut mrd = Mrd::new(mrd);
     println!("found raw dims: {:?}",mrd.dimension);
     mrd.load_volume(vidx);
-    let petab = Petable::new(petable);
+    let settings = PetableSettings{size_ky:Some(pesize),size_kz:Some(pesize),compression:None};
+    let petab = Petable::open(petable,&settings).unwrap_or_else(|e| panic!("{}",e));
     mrd.write_zero_filled_cfl(cfl,&petab);
 }
 
@@ -29,6 +35,46 @@ const OFFSET_TO_DATA:usize = 512;
 const HEADER_SIZE:usize = 256;
 const CHARCODE_BYTES:Range<usize> = 18..20;
 
+/*
+VolumeAddress
+names a volume by the header dimensions that are flattened into num_vols. dim[3] holds repeated
+averages, dim[4] echoes and dim[5] experiments. Averages vary fastest in the file, then echoes,
+then experiments. Parses from and prints to "echo=3,experiment=2,average=0"
+*/
+#[derive(Debug,Clone,Copy,PartialEq,Eq,Hash,Default)]
+pub struct VolumeAddress{
+    pub echo:usize,
+    pub experiment:usize,
+    pub average:usize,
+}
+
+impl FromStr for VolumeAddress{
+    type Err = String;
+    fn from_str(s:&str) -> Result<Self,Self::Err>{
+        let mut addr = VolumeAddress::default();
+        for field in s.split(',').map(|f| f.trim()).filter(|f| !f.is_empty()){
+            let (key,val) = match field.split_once(['=',':']){
+                Some(kv) => kv,
+                None => return Err(format!("volume address field {} is not of the form key=value",field))
+            };
+            let val:usize = val.trim().parse().map_err(|_| format!("cannot parse {} as a volume index",val))?;
+            match key.trim(){
+                "echo" | "e" => addr.echo = val,
+                "experiment" | "x" => addr.experiment = val,
+                "average" | "a" => addr.average = val,
+                _ => return Err(format!("unknown volume address field {}. Expected echo, experiment or average",key))
+            }
+        }
+        return Ok(addr);
+    }
+}
+
+impl fmt::Display for VolumeAddress{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f,"echo={},experiment={},average={}",self.echo,self.experiment,self.average)
+    }
+}
+
 #[derive(Debug)]
 pub struct Mrd{
     pub dimension:[i32;6],
@@ -40,7 +86,10 @@ pub struct Mrd{
     pub data_bytes:usize,
     pub bytes_per_vol:usize,
     pub num_vols:i32,
-    file:File,
+    pub channels:usize,
+    /* how views listed more than once in a stream table are combined */
+    pub zero_fill_mode:ZeroFillMode,
+    map:Mmap,
     pub vol_bytes:Vec<u8>,
     pub zero_filled:Vec<u8>,
     pub zero_filled_dimension:[i32;3],
@@ -49,40 +98,117 @@ pub struct Mrd{
 
 impl Mrd{
 
-    /* Load volume bytes from an offset */
+    /* Load volume bytes from an offset into an owned buffer */
     pub fn load_volume(&mut self,vol_idx:u16){
-        let mut reader = BufReader::new(&mut self.file);
-        let vol_byte_offset = self.bytes_per_vol*vol_idx as usize + OFFSET_TO_DATA;
-        reader.seek(SeekFrom::Start(vol_byte_offset as u64)).expect("Problem reaching data... is the file corrupt?");
         println!("reading mrd bits with offset {} ...",vol_idx);
-        reader.read_exact(&mut self.vol_bytes).expect("an issue occured reading mrd bytes... is the file corrupt?");
+        self.vol_bytes = self.volume_bytes(vol_idx as usize).to_vec();
         self.is_loaded = true;
     }
 
+    /*
+    volume_bytes
+    zero-copy view of one volume straight from the memory map. Only the pages backing
+    this volume are read from disk, so any number of volumes can be viewed at once
+    */
+    pub fn volume_bytes(&self,vol_idx:usize) -> &[u8]{
+        if vol_idx >= self.num_vols as usize {
+            panic!("volume {} requested but the mrd only has {} volumes",vol_idx,self.num_vols);
+        }
+        let start = OFFSET_TO_DATA + self.bytes_per_vol*vol_idx;
+        return self.mapped_range(start,self.bytes_per_vol);
+    }
+
+    /* the complete views of a volume that are on disk, for reconstructing truncated files */
+    pub fn available_volume_bytes(&self,vol_idx:usize) -> &[u8]{
+        if vol_idx >= self.num_vols as usize {
+            panic!("volume {} requested but the mrd only has {} volumes",vol_idx,self.num_vols);
+        }
+        let start = OFFSET_TO_DATA + self.bytes_per_vol*vol_idx;
+        let available = self.map.len().saturating_sub(start).min(self.bytes_per_vol);
+        let available = available - available % self.bytes_per_view();
+        return &self.map[start.min(self.map.len())..start.min(self.map.len()) + available];
+    }
+
+    /* zero-copy view of a single readout (view) of a volume */
+    pub fn view_bytes(&self,vol_idx:usize,view_idx:usize) -> &[u8]{
+        let view_bytes = self.bytes_per_view();
+        let n_views = (self.dimension[1]*self.dimension[2]) as usize;
+        if view_idx >= n_views {
+            panic!("view {} requested but volumes only have {} views",view_idx,n_views);
+        }
+        let vol = self.volume_bytes(vol_idx);
+        return &vol[view_bytes*view_idx..view_bytes*(view_idx+1)];
+    }
+
+    /* zero-copy view of one slice (all views sharing a views_2 index) of a volume */
+    pub fn slice_bytes(&self,vol_idx:usize,slice_idx:usize) -> &[u8]{
+        let slice_bytes = self.bytes_per_view()*self.dimension[1] as usize;
+        if slice_idx >= self.dimension[2] as usize {
+            panic!("slice {} requested but volumes only have {} slices",slice_idx,self.dimension[2]);
+        }
+        let vol = self.volume_bytes(vol_idx);
+        return &vol[slice_bytes*slice_idx..slice_bytes*(slice_idx+1)];
+    }
+
+    /*
+    Receiver channels are interleaved per view: each acquired view holds one readout per channel,
+    so a volume has dim[1]*dim[2]/channels distinct views
+    */
+    pub fn set_channels(&mut self,channels:usize){
+        let n_readouts = (self.dimension[1]*self.dimension[2]) as usize;
+        if channels == 0 || n_readouts % channels != 0 {
+            panic!("{} readouts per volume cannot be split into {} channels",n_readouts,channels);
+        }
+        self.channels = channels;
+    }
+
+    pub fn bytes_per_view(&self) -> usize{
+        return self.dimension[0] as usize*self.charbytes*(self.is_complex as usize + 1);
+    }
+
+    fn mapped_range(&self,start:usize,len:usize) -> &[u8]{
+        if start + len > self.map.len() {
+            panic!("mrd is {} bytes but data up to byte {} was requested. Is the file truncated?",self.map.len(),start+len);
+        }
+        return &self.map[start..start+len];
+    }
+
     pub fn write_out(&self,filename:&str){
         let out_path = Path::new(filename);
         let mut o = File::create(out_path).expect(&format!("trouble making output file {}",filename));
         o.write_all(&self.vol_bytes).expect("problem occured writing to file");
     }
 
+    /* Complex f32 view of the loaded volume, regardless of the stored data type */
     pub fn to_f32(&mut self) -> Vec<f32>{
         if !self.is_loaded {self.load_volume(0)};
-        let mut floats:Vec<f32> = vec![0.0;self.bytes_per_vol/size_of::<f32>()];
-        LittleEndian::read_f32_into(&self.vol_bytes,&mut floats);
-        return floats;
+        return self.decode_complex_f32(&self.vol_bytes);
     }
 
+    /* Store complex f32 values back into the volume buffer in the native data type */
     pub fn set_bytes_from_f32(&mut self,floats:&Vec<f32>){
-        LittleEndian::write_f32_into(floats,&mut self.vol_bytes);
+        let bytes = self.encode_complex_f32(floats);
+        self.vol_bytes.copy_from_slice(&bytes);
+    }
+
+    /* converts raw characters of this mrd's charcode to interleaved complex floats (re,im,re,im ...) */
+    pub fn decode_complex_f32(&self,bytes:&[u8]) -> Vec<f32>{
+        return decode_complex_f32(bytes,self.charcode,self.is_complex);
+    }
+
+    /* inverse of decode_complex_f32 */
+    pub fn encode_complex_f32(&self,floats:&[f32]) -> Vec<u8>{
+        return encode_complex_f32(floats,self.charcode,self.is_complex);
     }
 
     pub fn new(path:&str) -> Mrd {
 
-        /* Open file and get header into memory */
+        /* Map the file and get header from it */
         let mrd_file = Mrd::open(path);
-        let mut mrd_reader = BufReader::new(&mrd_file);
-        let mut header_bytes = [0;HEADER_SIZE];
-        mrd_reader.read_exact(&mut header_bytes).expect("a problem occured reading mrd header");
+        // the mapping is read-only. Writers appending to the file won't invalidate the mapped range
+        let map = unsafe { Mmap::map(&mrd_file).expect("a problem occured memory mapping the mrd") };
+        if map.len() < HEADER_SIZE {panic!("mrd is too small to contain a header. The file may be corrupt")}
+        let header_bytes = &map[0..HEADER_SIZE];
         /* Determine data dimesions from header */
         let mut dimension:[i32;6] = [0;6];
         dimension.iter_mut().enumerate()
@@ -102,14 +228,7 @@ impl Mrd{
         let mut charcode = utils::bytes_to_int(&header_bytes[CHARCODE_BYTES]);
         let is_complex = if charcode >= 16 {true} else {false};
         if is_complex {charcode -= 16};
-        let charbytes:usize = match charcode{
-            0 | 1 => 1,
-            2 | 3 => 2,
-            4 | 5 => 4,
-            6 => 8,
-            _ => panic!("problem determining character bytes. mrd may be currupt"),
-        };
-        if charbytes != 4 || !is_complex {panic!("only complex floats are supported for now")}
+        let charbytes = charbytes(charcode);
 
         /* Parse extra info that may be useful */
         let mut numel = 1;
@@ -120,7 +239,7 @@ impl Mrd{
         let num_vols = dimension[3]*dimension[4]*dimension[5];
         let bytes_per_vol = data_bytes/(num_vols as usize);
 
-        return Mrd{
+        let mut mrd = Mrd{
             dimension:dimension,
             is_complex:is_complex,
             charbytes:charbytes,
@@ -130,13 +249,79 @@ impl Mrd{
             data_bytes:data_bytes,
             bytes_per_vol:bytes_per_vol,
             num_vols:num_vols,
-            file:mrd_file,
-            vol_bytes:vec![0;bytes_per_vol],
+            channels:1,
+            zero_fill_mode:ZeroFillMode::Average,
+            map:map,
+            vol_bytes:Vec::new(),
             is_loaded:false,
             zero_filled:Vec::new(),
             zero_filled_dimension:[1,1,1],
         };
 
+        /* receiver count comes from the parameter trailer when the scanner wrote one */
+        let params = mrd.parameters();
+        let receivers = params.get("NO_RECEIVERS").or(params.get("no_receivers")).and_then(|v| v.as_i64());
+        match receivers {
+            Some(n) => if n > 1 {mrd.set_channels(n as usize)},
+            None => println!("warning: {} doesn't give NO_RECEIVERS. Assuming 1 receiver channel, set channels in the project settings if there are more",path),
+        }
+        return mrd;
+    }
+
+    /* Read and parse the text parameter trailer that follows the sample data */
+    pub fn parameters(&self) -> MrdParameters{
+        let trailer_offset = OFFSET_TO_DATA + self.data_bytes;
+        if trailer_offset > self.map.len() {return MrdParameters::default()}
+        return MrdParameters::parse(&String::from_utf8_lossy(&self.map[trailer_offset..]));
+    }
+
+    pub fn n_averages(&self) -> usize{
+        return self.dimension[3].max(1) as usize;
+    }
+
+    pub fn n_echoes(&self) -> usize{
+        return self.dimension[4].max(1) as usize;
+    }
+
+    pub fn n_experiments(&self) -> usize{
+        return self.dimension[5].max(1) as usize;
+    }
+
+    /* flat volume offset of a named volume */
+    pub fn volume_index(&self,addr:&VolumeAddress) -> usize{
+        if addr.average >= self.n_averages() || addr.echo >= self.n_echoes() || addr.experiment >= self.n_experiments(){
+            panic!("volume {} is out of range. The mrd has {} echoes, {} experiments and {} averages",
+                addr,self.n_echoes(),self.n_experiments(),self.n_averages());
+        }
+        return addr.average + self.n_averages()*(addr.echo + self.n_echoes()*addr.experiment);
+    }
+
+    pub fn volume_address(&self,vol_idx:usize) -> VolumeAddress{
+        return VolumeAddress{
+            average:vol_idx % self.n_averages(),
+            echo:(vol_idx / self.n_averages()) % self.n_echoes(),
+            experiment:vol_idx / (self.n_averages()*self.n_echoes()),
+        };
+    }
+
+    /* every addressable volume in file order */
+    pub fn addresses(&self) -> Vec<VolumeAddress>{
+        return (0..self.num_vols as usize).map(|i| self.volume_address(i)).collect();
+    }
+
+    /* resolve a volume from either a plain offset ("4") or an address ("echo=3,experiment=2") */
+    pub fn resolve_volume(&self,selector:&str) -> usize{
+        let vol_idx = match selector.trim().parse::<usize>(){
+            Ok(offset) => offset,
+            Err(_) => {
+                let addr:VolumeAddress = selector.parse().unwrap_or_else(|e| panic!("{}",e));
+                self.volume_index(&addr)
+            }
+        };
+        if vol_idx >= self.num_vols as usize {
+            panic!("volume {} requested but the mrd only has {} volumes",vol_idx,self.num_vols);
+        }
+        return vol_idx;
     }
 
     pub fn data_type(&self) -> String{
@@ -159,42 +344,135 @@ impl Mrd{
         return handle
     }
 
-    fn complex_mult(&self) -> usize{
-        return self.is_complex as usize + 1;
-    }
-
+    /* shape of one volume as (views,samples,re/im) in the complex f32 view */
     pub fn dim_tuple(&self) -> (usize,usize,usize){
         return (
             (self.dimension[1]*self.dimension[2]) as usize,
             self.dimension[0] as usize,
-            self.complex_mult()
+            2
         )
     }
 
+    /* number of f32 values in one volume of the complex f32 view */
     pub fn numel(&self) -> usize{
-        let n_points = self.dimension[0]*self.dimension[1]*self.dimension[3];
-        return n_points as usize * self.complex_mult();
+        let n_points = self.dimension[0]*self.dimension[1]*self.dimension[2];
+        return n_points as usize * 2;
     }
 
     pub fn zero_fill(&mut self,pe_table:&Petable) -> Vec<f32>{
-        // Array for raw floats
-        let mut raw_floats:Vec<f32> = vec![0.0;self.numel()];
-        LittleEndian::read_f32_into(&self.vol_bytes,&mut raw_floats);
-        let raw_arr = Array3::from_shape_vec(self.dim_tuple(), raw_floats).expect("raw floats cannot fit into shape");
+        if !self.is_loaded {self.load_volume(0)};
+        return self.zero_fill_bytes(&self.vol_bytes,&pe_table.indices(),pe_table.pe_dims());
+    }
+
+    /*
+    zero-fill a volume directly from the memory map without loading it first. A partial
+    zero-fill only uses the views that made it to disk and leaves the rest empty
+    */
+    pub fn zero_fill_volume(&self,vol_idx:usize,pe_table:&Petable,partial:bool) -> Vec<f32>{
+        let indices = pe_table.indices();
+        let pe_dims = pe_table.pe_dims();
+        return match partial {
+            true => self.zero_fill_bytes(self.available_volume_bytes(vol_idx),&indices,pe_dims),
+            false => self.zero_fill_bytes(self.volume_bytes(vol_idx),&indices,pe_dims)
+        };
+    }
+
+    /*
+    zero_fill_bytes
+    places each view of the volume into a (pe,pe,readout,re/im) row-major buffer of pe_dims phase encodes.
+    Views are decoded one at a time so the only full-size allocation is the zero-filled result.
+    With several receivers the readouts of one view are expected back to back, channel 0 first:
+        view 0 ch 0, view 0 ch 1, ... view 0 ch n-1, view 1 ch 0, ...
+    so the header's view count is the table length times the channel count. This is the layout
+    set_channels assumes; test_channels only covers a synthetic file, so check a multi-receiver scan
+    against a single-receiver one of the same phantom before trusting a new sequence
+    */
+    fn zero_fill_bytes(&self,vol_bytes:&[u8],indices:&[(usize,usize)],pe_dims:[usize;2]) -> Vec<f32>{
         let r = self.dimension[0] as usize;
-        let zf_dims = (pe_table.size,pe_table.size,r,self.complex_mult());
-        let numel = zf_dims.0*zf_dims.1*zf_dims.2*zf_dims.3;
-        let mut zf_arr = Array4::<f32>::zeros(zf_dims);
+        let readout_bytes = self.bytes_per_view();
+        let view_bytes = readout_bytes*self.channels;
+        let line_len = 2*r;
+        let coil_len = pe_dims[0]*pe_dims[1]*line_len;
+        let mut zf:Vec<f32> = vec![0.0;coil_len*self.channels];
         println!("zero-filling compressed data ...");
-        let indices = pe_table.indices();
-        for (i,index) in indices.iter().enumerate() {
-            let mut zf_slice = zf_arr.slice_mut(s![index.0,index.1,..,..]);
-            zf_slice += &raw_arr.slice(s![i,..,..]);
+        let n_views = vol_bytes.len()/view_bytes;
+        if n_views < indices.len(){
+            println!("only {} of {} views are available. The remaining views are left empty",n_views,indices.len());
+        }
+        if let Some((i,index)) = indices.iter().enumerate().find(|(_,index)| index.0 >= pe_dims[0] || index.1 >= pe_dims[1]){
+            panic!("phase encode table entry {} lands at {:?}, outside the {:?} grid. Run check-petable on the table",i,index,pe_dims);
+        }
+        let counts = sample_counts(indices,n_views,pe_dims);
+        let mut placed = vec![0u32;counts.len()];
+        for (i,index) in indices.iter().take(n_views).enumerate() {
+            let position = index.0*pe_dims[1] + index.1;
+            placed[position] += 1;
+            let weight = match self.zero_fill_mode{
+                ZeroFillMode::Average => 1.0/counts[position] as f32,
+                ZeroFillMode::Sum => 1.0,
+                ZeroFillMode::KeepFirst if placed[position] > 1 => continue,
+                ZeroFillMode::KeepLast if placed[position] < counts[position] => continue,
+                ZeroFillMode::KeepFirst | ZeroFillMode::KeepLast => 1.0,
+            };
+            for c in 0..self.channels {
+                let readout_start = i*view_bytes + c*readout_bytes;
+                let readout = self.decode_complex_f32(&vol_bytes[readout_start..readout_start+readout_bytes]);
+                let offset = c*coil_len + position*line_len;
+                zf[offset..offset+line_len].iter_mut().zip(readout).for_each(|(z,v)| *z += weight*v);
+            }
         }
-        println!("reshaping zero-filled ...");
-        let flat = zf_arr.to_shape((numel,Order::RowMajor)).expect("unexpected data size");
-        println!("flattening ...");
-        return flat.to_vec();
+        return zf;
+    }
+
+    /*
+    sample_count_map
+    how many views land on each phase encode, as a bart pattern ([1,ky,kz]). Only views that made it to
+    disk are counted for a partial volume
+    */
+    pub fn sample_count_map(&self,vol_idx:usize,pe_table:&Petable,partial:bool) -> Vec<u32>{
+        let vol_bytes = match partial {
+            true => self.available_volume_bytes(vol_idx).len(),
+            false => self.bytes_per_vol,
+        };
+        let n_views = vol_bytes/(self.bytes_per_view()*self.channels);
+        return sample_counts(&pe_table.indices(),n_views,pe_table.pe_dims());
+    }
+
+    /*
+    sample_weights
+    data consistency weights for a zero-filled volume. An average of n views has 1/n the noise
+    variance, so it is weighted by sqrt(n). Summed or kept views are only masked
+    */
+    pub fn sample_weights(&self,counts:&[u32]) -> Vec<f32>{
+        return counts.iter().map(|n| match self.zero_fill_mode{
+            ZeroFillMode::Average => (*n as f32).sqrt(),
+            _ => (*n > 0) as u8 as f32,
+        }).collect();
+    }
+
+    /* cfl dimensions of zero-filled data. Channels land in the bart coil dimension (dim 3) */
+    pub fn zero_filled_dims(&self,pe_table:&Petable) -> [usize;4]{
+        return [self.dimension[0] as usize,pe_table.size_ky,pe_table.size_kz,self.channels];
+    }
+
+    /*
+    cfl dimensions of a fully sampled cartesian volume: every view of a slice, then every slice.
+    dim[1] counts the readouts of all channels, so it holds dim[1]/channels phase encodes
+    */
+    pub fn cartesian_dims(&self) -> [usize;4]{
+        return [self.dimension[0] as usize,self.dimension[1] as usize/self.channels,self.dimension[2] as usize,self.channels];
+    }
+
+    /* a fully sampled volume in the same layout as zero_fill_volume, with views in acquisition order */
+    pub fn cartesian_volume(&self,vol_idx:usize) -> Vec<f32>{
+        let dims = self.cartesian_dims();
+        let indices:Vec<(usize,usize)> = (0..dims[2]).flat_map(|slice| (0..dims[1]).map(move |view| (slice,view))).collect();
+        return self.zero_fill_bytes(self.volume_bytes(vol_idx),&indices,[dims[2],dims[1]]);
+    }
+
+    pub fn write_cartesian_volume_cfl(&self,vol_idx:usize,filename:&str){
+        let kspace = self.cartesian_volume(vol_idx);
+        self.write_cfl_vol_from_vec(filename,&kspace,&self.cartesian_dims());
     }
 
     pub fn raw_ndarray(&self){
@@ -203,23 +481,23 @@ impl Mrd{
 
     pub fn write_zero_filled_cfl(&mut self,filename:&str,pe_table:&Petable){
         let zf = self.zero_fill(pe_table);
-        let dims = (self.dimension[0] as usize,pe_table.size,pe_table.size);
-        self.write_cfl_vol_from_vec(filename,&zf,dims);
+        self.write_cfl_vol_from_vec(filename,&zf,&self.zero_filled_dims(pe_table));
     }
 
-    fn write_cfl_vol_from_vec(&self,filepath:&str,data:&Vec<f32>,dim:(usize,usize,usize)){
+    /* also writes the sample count map and weights next to the k-space as <filename>_counts and <filename>_weights */
+    pub fn write_zero_filled_volume_cfl(&self,vol_idx:usize,filename:&str,pe_table:&Petable,partial:bool){
+        let zf = self.zero_fill_volume(vol_idx,pe_table,partial);
+        self.write_cfl_vol_from_vec(filename,&zf,&self.zero_filled_dims(pe_table));
+        let counts = self.sample_count_map(vol_idx,pe_table,partial);
+        let pattern_dims = [1,pe_table.size_ky,pe_table.size_kz];
+        let as_complex = |v:Vec<f32>| -> Vec<f32>{v.iter().flat_map(|x| [*x,0.0]).collect()};
+        cfl::write(Path::new(&format!("{}_counts",filename)),&as_complex(counts.iter().map(|c| *c as f32).collect()),&pattern_dims);
+        cfl::write(Path::new(&weights_cfl(filename)),&as_complex(self.sample_weights(&counts)),&pattern_dims);
+    }
+
+    fn write_cfl_vol_from_vec(&self,filepath:&str,data:&Vec<f32>,dims:&[usize]){
         println!("writing to cfl ...");
-        let base = Path::new(filepath);
-        let cfl_raw = base.with_extension("cfl");
-        let cfl_header = base.with_extension("hdr");
-        let mut raw_f = File::create(cfl_raw).expect("cannot create file");
-        let mut header_f = File::create(cfl_header).expect("cannot create file");
-        let nbytes = self.complex_mult()*dim.0*dim.1*dim.2*self.charbytes;
-        let mut bytebuff:Vec<u8> = vec![0;nbytes];
-        LittleEndian::write_f32_into(data,&mut bytebuff);
-        raw_f.write_all(&bytebuff).expect("a problem occured writing to cfl raw");
-        let hdr_str = format!("# Dimensions\n{} {} {} 1 1",dim.0,dim.1,dim.2);
-        header_f.write_all(hdr_str.as_bytes()).expect("a problem occured writing to cfl header");
+        cfl::write(Path::new(filepath),data,dims);
     }
 
 }
@@ -228,4 +506,437 @@ impl fmt::Display for Mrd {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "dims: {:?}\nn_volumes: {}", self.dimension, self.num_vols)
     }
-}
\ No newline at end of file
+}
+/*
+IncompleteMrdPolicy
+what to do with an mrd that is truncated or still being written
+    Skip: once the file stops growing, leave its incomplete volumes out of the reconstruction for good
+    Wait: hold off until the file is complete (the default)
+    Partial: once the file stops growing, reconstruct whatever views made it to disk
+*/
+#[derive(Debug,Clone,Copy,PartialEq,Eq,Serialize,Deserialize,Default)]
+pub enum IncompleteMrdPolicy{
+    Skip,
+    #[default]
+    Wait,
+    Partial,
+}
+
+/*
+ZeroFillMode
+how views that a stream table lists more than once are combined in a zero-filled volume
+    Average: mean of the repeated views (the default)
+    KeepFirst/KeepLast: only the first or last acquisition of a position is used
+    Sum: views are added, which scales repeated positions by their count
+*/
+#[derive(Debug,Clone,Copy,PartialEq,Eq,Serialize,Deserialize,Default)]
+pub enum ZeroFillMode{
+    #[default]
+    Average,
+    KeepFirst,
+    KeepLast,
+    Sum,
+}
+
+/* views landing on each position of a pe_dims grid, index.1 fastest. Only the first n_views entries count */
+fn sample_counts(indices:&[(usize,usize)],n_views:usize,pe_dims:[usize;2]) -> Vec<u32>{
+    let mut counts = vec![0u32;pe_dims[0]*pe_dims[1]];
+    indices.iter().take(n_views).filter(|(a,b)| *a < pe_dims[0] && *b < pe_dims[1])
+        .for_each(|(a,b)| counts[a*pe_dims[1] + b] += 1);
+    return counts;
+}
+
+/* time between the two size checks that tell if an mrd is still growing */
+pub const MRD_SETTLE:Duration = Duration::from_secs(2);
+
+/* Result of comparing the bytes an mrd header promises with what is on disk */
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct MrdValidation{
+    pub expected_bytes:usize,
+    pub actual_bytes:usize,
+    pub total_views:usize,
+    pub complete_views:usize,
+    pub total_volumes:usize,
+    pub complete_volumes:usize,
+    pub in_progress:bool,
+}
+
+impl MrdValidation{
+
+    /*
+    validate
+    checks an mrd file against its header. The file size is sampled twice, settle apart, to
+    see if the file is still growing (scan running or copy in progress)
+    */
+    pub fn new(path:&str,settle:Duration) -> MrdValidation{
+        let file_size = |p:&str| std::fs::metadata(p).expect("cannot read mrd file metadata").len() as usize;
+        let first_size = file_size(path);
+        if !settle.is_zero() {std::thread::sleep(settle)}
+        let actual_bytes = file_size(path);
+        let in_progress = actual_bytes != first_size;
+        if actual_bytes < HEADER_SIZE {
+            return MrdValidation{
+                expected_bytes:OFFSET_TO_DATA,
+                actual_bytes:actual_bytes,
+                total_views:0,
+                complete_views:0,
+                total_volumes:0,
+                complete_volumes:0,
+                in_progress:in_progress
+            };
+        }
+        let mrd = Mrd::new(path);
+        let available = actual_bytes.saturating_sub(OFFSET_TO_DATA).min(mrd.data_bytes);
+        return MrdValidation{
+            expected_bytes:OFFSET_TO_DATA + mrd.data_bytes,
+            actual_bytes:actual_bytes,
+            total_views:mrd.data_bytes/mrd.bytes_per_view(),
+            complete_views:available/mrd.bytes_per_view(),
+            total_volumes:mrd.num_vols as usize,
+            complete_volumes:available/mrd.bytes_per_vol,
+            in_progress:in_progress
+        };
+    }
+
+    pub fn is_complete(&self) -> bool{
+        return !self.in_progress && self.total_volumes > 0 && self.complete_volumes == self.total_volumes;
+    }
+
+    pub fn volume_is_complete(&self,vol_idx:usize) -> bool{
+        return vol_idx < self.complete_volumes;
+    }
+
+    /* true if at least one view of the volume is on disk */
+    pub fn volume_has_views(&self,vol_idx:usize) -> bool{
+        if self.total_volumes == 0 || vol_idx >= self.total_volumes {return false}
+        let views_per_vol = self.total_views/self.total_volumes;
+        return self.complete_views > vol_idx*views_per_vol;
+    }
+
+    /* decide if a volume can be reconstructed now under a policy */
+    pub fn volume_is_ready(&self,vol_idx:usize,policy:IncompleteMrdPolicy) -> bool{
+        if self.volume_is_complete(vol_idx) {return true}
+        return match policy {
+            IncompleteMrdPolicy::Partial => !self.in_progress && self.volume_has_views(vol_idx),
+            _ => false
+        };
+    }
+}
+
+impl fmt::Display for MrdValidation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = if self.in_progress {"still being written"}
+            else if self.is_complete() {"complete"}
+            else {"truncated"};
+        write!(f,"mrd is {}: expected {} bytes, found {} bytes. {} of {} views and {} of {} volumes are complete",
+            state,self.expected_bytes,self.actual_bytes,self.complete_views,self.total_views,self.complete_volumes,self.total_volumes)
+    }
+}
+
+fn charbytes(charcode:i16) -> usize{
+    return match charcode{
+        0 | 1 => 1,
+        2 | 3 => 2,
+        4 | 5 => 4,
+        6 => 8,
+        _ => panic!("problem determining character bytes. mrd may be currupt"),
+    };
+}
+
+/*
+decode_complex_f32
+converts raw mrd characters of any charcode to interleaved complex floats (re,im,re,im ...).
+Real-only data gets an imaginary part of zero
+*/
+pub fn decode_complex_f32(bytes:&[u8],charcode:i16,is_complex:bool) -> Vec<f32>{
+    let n_chars = bytes.len()/charbytes(charcode);
+    let mut chars:Vec<f32> = vec![0.0;n_chars];
+    match charcode{
+        0 => chars.iter_mut().zip(bytes).for_each(|(c,b)| *c = *b as f32),
+        1 => chars.iter_mut().zip(bytes).for_each(|(c,b)| *c = *b as i8 as f32),
+        2 | 3 => {
+            let mut ints:Vec<i16> = vec![0;n_chars];
+            LittleEndian::read_i16_into(bytes,&mut ints);
+            chars.iter_mut().zip(ints).for_each(|(c,i)| *c = i as f32);
+        }
+        4 => {
+            let mut longs:Vec<i32> = vec![0;n_chars];
+            LittleEndian::read_i32_into(bytes,&mut longs);
+            chars.iter_mut().zip(longs).for_each(|(c,l)| *c = l as f32);
+        }
+        5 => LittleEndian::read_f32_into(bytes,&mut chars),
+        6 => {
+            let mut doubles:Vec<f64> = vec![0.0;n_chars];
+            LittleEndian::read_f64_into(bytes,&mut doubles);
+            chars.iter_mut().zip(doubles).for_each(|(c,d)| *c = d as f32);
+        }
+        _ => panic!("problem determining character bytes. mrd may be currupt"),
+    }
+    if is_complex {return chars}
+    let mut complex:Vec<f32> = vec![0.0;2*n_chars];
+    complex.chunks_exact_mut(2).zip(chars).for_each(|(z,re)| z[0] = re);
+    return complex;
+}
+
+/*
+encode_complex_f32
+inverse of decode_complex_f32. Integer types are rounded and saturated, and the imaginary
+part is dropped for real-only data
+*/
+pub fn encode_complex_f32(floats:&[f32],charcode:i16,is_complex:bool) -> Vec<u8>{
+    let chars:Vec<f32> = match is_complex{
+        true => floats.to_vec(),
+        false => floats.chunks_exact(2).map(|z| z[0]).collect()
+    };
+    let mut bytes:Vec<u8> = vec![0;chars.len()*charbytes(charcode)];
+    match charcode{
+        0 => bytes.iter_mut().zip(&chars).for_each(|(b,c)| *b = c.round() as u8),
+        1 => bytes.iter_mut().zip(&chars).for_each(|(b,c)| *b = c.round() as i8 as u8),
+        2 | 3 => {
+            let ints:Vec<i16> = chars.iter().map(|c| c.round() as i16).collect();
+            LittleEndian::write_i16_into(&ints,&mut bytes);
+        }
+        4 => {
+            let longs:Vec<i32> = chars.iter().map(|c| c.round() as i32).collect();
+            LittleEndian::write_i32_into(&longs,&mut bytes);
+        }
+        5 => LittleEndian::write_f32_into(&chars,&mut bytes),
+        6 => {
+            let doubles:Vec<f64> = chars.iter().map(|c| *c as f64).collect();
+            LittleEndian::write_f64_into(&doubles,&mut bytes);
+        }
+        _ => panic!("problem determining character bytes. mrd may be currupt"),
+    }
+    return bytes;
+}
+
+/*
+MrdWriter
+builds mrd files from rust. The header carries the dimensions and data type, the samples follow at
+OFFSET_TO_DATA and an optional parameter trailer is appended after the samples
+*/
+#[derive(Debug,Clone)]
+pub struct MrdWriter{
+    pub dimension:[i32;6],
+    pub charcode:i16,
+    pub is_complex:bool,
+    pub parameters:Option<MrdParameters>,
+}
+
+impl MrdWriter{
+
+    /* complex float is assumed until the data type is set */
+    pub fn new(dimension:[i32;6]) -> MrdWriter{
+        return MrdWriter{
+            dimension:dimension,
+            charcode:5,
+            is_complex:true,
+            parameters:None
+        };
+    }
+
+    /* writer matching the layout, data type and parameters of an existing mrd */
+    pub fn from_mrd(mrd:&Mrd) -> MrdWriter{
+        let params = mrd.parameters();
+        return MrdWriter{
+            dimension:mrd.dimension,
+            charcode:mrd.charcode,
+            is_complex:mrd.is_complex,
+            parameters:if params.is_empty() {None} else {Some(params)}
+        };
+    }
+
+    pub fn set_data_type(&mut self,charcode:i16,is_complex:bool) -> &mut Self{
+        charbytes(charcode);
+        self.charcode = charcode;
+        self.is_complex = is_complex;
+        return self;
+    }
+
+    pub fn set_parameters(&mut self,parameters:MrdParameters) -> &mut Self{
+        self.parameters = Some(parameters);
+        return self;
+    }
+
+    pub fn data_bytes(&self) -> usize{
+        let mut numel = 1;
+        self.dimension.iter().for_each(|d| numel *= *d as usize);
+        return numel*charbytes(self.charcode)*(self.is_complex as usize + 1);
+    }
+
+    pub fn header(&self) -> Vec<u8>{
+        let mut header:Vec<u8> = vec![0;OFFSET_TO_DATA];
+        LittleEndian::write_i32_into(&self.dimension[0..4],&mut header[0..16]);
+        LittleEndian::write_i32_into(&self.dimension[4..6],&mut header[152..160]);
+        let charcode = if self.is_complex {self.charcode + 16} else {self.charcode};
+        LittleEndian::write_i16(&mut header[CHARCODE_BYTES],charcode);
+        return header;
+    }
+
+    /* write complex f32 samples for every volume, encoded in the writer's data type */
+    pub fn write_complex_f32(&self,filename:&str,data:&[f32]){
+        let bytes = encode_complex_f32(data,self.charcode,self.is_complex);
+        self.write_bytes(filename,&bytes);
+    }
+
+    /* write samples that are already encoded in the writer's data type */
+    pub fn write_bytes(&self,filename:&str,data:&[u8]){
+        if data.len() != self.data_bytes(){
+            panic!("expected {} bytes of sample data for dims {:?} but got {}",self.data_bytes(),self.dimension,data.len());
+        }
+        let mut f = File::create(Path::new(filename)).expect(&format!("trouble making output file {}",filename));
+        f.write_all(&self.header()).expect("problem occured writing mrd header");
+        f.write_all(data).expect("problem occured writing mrd data");
+        if let Some(params) = &self.parameters {
+            f.write_all(params.to_string().as_bytes()).expect("problem occured writing mrd parameters");
+        }
+    }
+}
+
+#[test]
+fn test_decode_short_real(){
+    // 4 samples x 2 views of real-only shorts
+    let samples:Vec<i16> = vec![1,-2,3,-4,5,-6,7,-8];
+    let mut bytes:Vec<u8> = vec![0;2*samples.len()];
+    LittleEndian::write_i16_into(&samples,&mut bytes);
+    let path = std::env::temp_dir().join("cs_reco_test_decode_short_real.mrd");
+    let mut w = MrdWriter::new([4,2,1,1,1,1]);
+    w.set_data_type(2,false);
+    w.write_bytes(path.to_str().unwrap(),&bytes);
+
+    let mut mrd = Mrd::new(path.to_str().unwrap());
+    assert_eq!(mrd.data_type(),"short");
+    let floats = mrd.to_f32();
+    let expected:Vec<f32> = samples.iter().flat_map(|s| [*s as f32,0.0]).collect();
+    assert_eq!(floats,expected);
+    mrd.set_bytes_from_f32(&floats);
+    assert_eq!(mrd.to_f32(),expected);
+}
+
+#[test]
+fn test_write_round_trip(){
+    let dims = [8,6,1,2,1,1];
+    let n = 2*(8*6*2) as usize;
+    let data:Vec<f32> = (0..n).map(|i| i as f32 - 100.0).collect();
+    let params = MrdParameters::parse(":VAR no_samples, 8\n:VAR no_views, 6\n");
+    let path = std::env::temp_dir().join("cs_reco_test_write_round_trip.mrd");
+    let path = path.to_str().unwrap();
+    for (charcode,is_complex) in [(5,true),(2,true),(6,false)]{
+        let mut w = MrdWriter::new(dims);
+        w.set_data_type(charcode,is_complex).set_parameters(params.clone());
+        w.write_complex_f32(path,&data);
+        let mrd = Mrd::new(path);
+        assert_eq!(mrd.dimension,dims);
+        assert_eq!(mrd.num_vols,2);
+        let vol1 = mrd.decode_complex_f32(mrd.volume_bytes(1));
+        let expected:Vec<f32> = data[n/2..].chunks_exact(2).flat_map(|z| [z[0],if is_complex {z[1]} else {0.0}]).collect();
+        assert_eq!(vol1,expected);
+        assert_eq!(mrd.parameters().entries,params.entries);
+        assert_eq!(MrdWriter::from_mrd(&mrd).header(),w.header());
+    }
+}
+
+#[test]
+fn test_volume_address(){
+    let path = std::env::temp_dir().join("cs_reco_test_volume_address.mrd");
+    let path = path.to_str().unwrap();
+    // 2 averages, 3 echoes, 2 experiments
+    let w = MrdWriter::new([2,1,1,2,3,2]);
+    let n_vols = 12;
+    let data:Vec<f32> = (0..4*n_vols).map(|i| (i/4) as f32).collect();
+    w.write_complex_f32(path,&data);
+    let mrd = Mrd::new(path);
+    let addrs = mrd.addresses();
+    assert_eq!(addrs.len(),n_vols);
+    addrs.iter().enumerate().for_each(|(i,a)| assert_eq!(mrd.volume_index(a),i));
+    let vol = mrd.resolve_volume("echo=2,experiment=1");
+    assert_eq!(vol,10);
+    assert_eq!(mrd.decode_complex_f32(mrd.volume_bytes(vol))[0],10.0);
+    assert_eq!(mrd.resolve_volume("7"),7);
+    assert_eq!(mrd.volume_address(7).to_string(),"echo=0,experiment=1,average=1");
+}
+
+#[test]
+fn test_validation(){
+    let path = std::env::temp_dir().join("cs_reco_test_validation.mrd");
+    let path = path.to_str().unwrap();
+    // 3 volumes of 4 views with 2 samples each
+    let w = MrdWriter::new([2,4,1,3,1,1]);
+    w.write_complex_f32(path,&vec![1.0;2*2*4*3]);
+    let v = MrdValidation::new(path,Duration::ZERO);
+    assert!(v.is_complete());
+    // chop off the last volume and a half
+    let view_bytes = 2*2*4;
+    let f = std::fs::OpenOptions::new().write(true).open(path).unwrap();
+    f.set_len((OFFSET_TO_DATA + view_bytes*6) as u64).unwrap();
+    let v = MrdValidation::new(path,Duration::ZERO);
+    assert!(!v.is_complete());
+    assert_eq!((v.complete_views,v.complete_volumes),(6,1));
+    assert!(v.volume_is_ready(1,IncompleteMrdPolicy::Partial));
+    assert!(!v.volume_is_ready(1,IncompleteMrdPolicy::Wait));
+    // nothing of the last volume made it to disk
+    assert!(!v.volume_is_ready(2,IncompleteMrdPolicy::Partial));
+    let mrd = Mrd::new(path);
+    assert_eq!(mrd.available_volume_bytes(1).len(),view_bytes*2);
+    assert_eq!(mrd.available_volume_bytes(2).len(),0);
+}
+
+#[test]
+fn test_channels(){
+    let dir = std::env::temp_dir();
+    let ptab = dir.join("stream_CS4_2x_channel_test");
+    // 3 views of a 4x4 table
+    File::create(&ptab).unwrap().write_all(b"-2\r\n-2\r\n0\r\n1\r\n1\r\n-1\r\n").unwrap();
+    let petab = Petable::new(ptab.to_str().unwrap()).unwrap();
+    let path = dir.join("cs_reco_test_channels.mrd");
+    let path = path.to_str().unwrap();
+    // 2 samples, 3 views x 2 channels. Readout values encode (view,channel)
+    let data:Vec<f32> = (0..6).flat_map(|readout| {
+        let v = (10*(readout/2) + readout%2) as f32;
+        [v,0.0,v,0.0]
+    }).collect();
+    let mut w = MrdWriter::new([2,6,1,1,1,1]);
+    w.set_parameters(MrdParameters::parse(":VAR NO_RECEIVERS, 2"));
+    w.write_complex_f32(path,&data);
+    let mrd = Mrd::new(path);
+    assert_eq!(mrd.channels,2);
+    assert_eq!(mrd.zero_filled_dims(&petab),[2,4,4,2]);
+    let zf = mrd.zero_fill_volume(0,&petab,false);
+    let at = |c:usize,i:usize,j:usize| zf[((c*4 + i)*4 + j)*4];
+    assert_eq!(at(0,0,0),0.0);
+    assert_eq!(at(1,0,0),1.0);
+    assert_eq!(at(0,2,3),10.0);
+    assert_eq!(at(1,3,1),21.0);
+}
+
+#[test]
+fn test_zero_fill_mode(){
+    let dir = std::env::temp_dir();
+    let ptab = dir.join("stream_CS4_2x_repeat_test");
+    // the center is listed three times, (0,1) once
+    File::create(&ptab).unwrap().write_all(b"0\n0\n0\n1\n0\n0\n0\n0\n").unwrap();
+    let petab = Petable::new(ptab.to_str().unwrap()).unwrap();
+    let path = dir.join("cs_reco_test_repeat.mrd");
+    let path = path.to_str().unwrap();
+    // 1 sample per view, valued 1,2,4,8 in acquisition order
+    let data:Vec<f32> = [1.0,2.0,4.0,8.0].iter().flat_map(|v| [*v,0.0]).collect();
+    MrdWriter::new([1,4,1,1,1,1]).write_complex_f32(path,&data);
+    let mut mrd = Mrd::new(path);
+    let center = |mrd:&Mrd| mrd.zero_fill_volume(0,&petab,false)[2*(2*4 + 2)];
+    assert_eq!(center(&mrd),13.0/3.0);
+    mrd.zero_fill_mode = ZeroFillMode::KeepFirst;
+    assert_eq!(center(&mrd),1.0);
+    mrd.zero_fill_mode = ZeroFillMode::KeepLast;
+    assert_eq!(center(&mrd),8.0);
+    mrd.zero_fill_mode = ZeroFillMode::Sum;
+    assert_eq!(center(&mrd),13.0);
+    assert_eq!(mrd.zero_fill_volume(0,&petab,false)[2*(2*4 + 3)],2.0);
+
+    let counts = mrd.sample_count_map(0,&petab,false);
+    assert_eq!((counts[2*4 + 2],counts[2*4 + 3],counts.iter().sum::<u32>()),(3,1,4));
+    mrd.zero_fill_mode = ZeroFillMode::Average;
+    assert_eq!(mrd.sample_weights(&counts)[2*4 + 2],3f32.sqrt());
+    let kspace = dir.join("cs_reco_test_repeat_kspace");
+    mrd.write_zero_filled_volume_cfl(0,kspace.to_str().unwrap(),&petab,false);
+    assert_eq!(cfl::get_all_dims(&dir.join("cs_reco_test_repeat_kspace_weights"))[..3],[1,4,4]);
+}
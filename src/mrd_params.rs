@@ -0,0 +1,150 @@
+use std::collections::HashMap;
+use std::fmt;
+
+/*
+    MrdParameters
+    The text trailer after the sample data of an mrd file holds the scan parameters (the PPR section).
+    Entries look like
+        :VAR no_samples, 480
+        :EDITTEXT LINE_TEXT1 "some text"
+        :VAR_ARRAY pe1_table, 1, 2, 3
+    where the token after the colon is the directive, followed by a name and one or more values.
+*/
+#[derive(Debug,Clone,PartialEq)]
+pub enum MrdValue{
+    Int(i64),
+    Float(f64),
+    Text(String),
+    List(Vec<MrdValue>),
+}
+
+#[derive(Debug,Clone,PartialEq)]
+pub struct MrdParameter{
+    pub directive:String,
+    pub name:String,
+    pub value:MrdValue,
+}
+
+#[derive(Debug,Clone,Default)]
+pub struct MrdParameters{
+    pub entries:Vec<MrdParameter>,
+}
+
+impl MrdValue{
+    fn parse(value_str:&str) -> MrdValue{
+        let value_str = value_str.trim();
+        if value_str.starts_with('"'){return MrdValue::Text(value_str.trim_matches('"').to_string())}
+        let vals:Vec<&str> = value_str.split(',').map(|v| v.trim()).filter(|v| !v.is_empty()).collect();
+        return match vals.len(){
+            0 => MrdValue::Text(String::new()),
+            1 => MrdValue::parse_scalar(vals[0]),
+            _ => MrdValue::List(vals.iter().map(|v| MrdValue::parse_scalar(v)).collect())
+        }
+    }
+
+    fn parse_scalar(s:&str) -> MrdValue{
+        if let Ok(i) = s.parse::<i64>(){return MrdValue::Int(i)}
+        if let Ok(f) = s.parse::<f64>(){return MrdValue::Float(f)}
+        return MrdValue::Text(s.trim_matches('"').to_string());
+    }
+
+    pub fn as_f64(&self) -> Option<f64>{
+        return match self{
+            MrdValue::Int(i) => Some(*i as f64),
+            MrdValue::Float(f) => Some(*f),
+            _ => None
+        }
+    }
+
+    pub fn as_i64(&self) -> Option<i64>{
+        return match self{
+            MrdValue::Int(i) => Some(*i),
+            _ => None
+        }
+    }
+}
+
+impl fmt::Display for MrdValue{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self{
+            MrdValue::Int(i) => write!(f,"{}",i),
+            MrdValue::Float(x) => write!(f,"{}",x),
+            MrdValue::Text(s) => write!(f,"{}",s),
+            MrdValue::List(l) => {
+                let strs:Vec<String> = l.iter().map(|v| v.to_string()).collect();
+                write!(f,"{}",strs.join(", "))
+            }
+        }
+    }
+}
+
+impl MrdParameters{
+
+    /* parse the text trailer of an mrd file. Lines that aren't parameter entries are ignored */
+    pub fn parse(trailer:&str) -> MrdParameters{
+        let mut entries = Vec::<MrdParameter>::new();
+        trailer.lines().for_each(|line|{
+            let line = line.trim();
+            if !line.starts_with(':'){return}
+            let line = &line[1..];
+            let (directive,rest) = match line.find(char::is_whitespace){
+                Some(idx) => line.split_at(idx),
+                None => return // a directive without a name carries no parameter
+            };
+            let rest = rest.trim();
+            let (name,value) = match rest.find(|c:char| c.is_whitespace() || c == ','){
+                Some(idx) => rest.split_at(idx),
+                None => (rest,"")
+            };
+            let value = value.trim_start_matches(|c:char| c.is_whitespace() || c == ',');
+            entries.push(MrdParameter{
+                directive:directive.to_string(),
+                name:name.to_string(),
+                value:MrdValue::parse(value)
+            });
+        });
+        return MrdParameters{entries:entries};
+    }
+
+    /* the last entry with this name wins, matching how the scanner applies them */
+    pub fn get(&self,name:&str) -> Option<&MrdValue>{
+        return self.entries.iter().rev().find(|e| e.name == name).map(|e| &e.value);
+    }
+
+    pub fn is_empty(&self) -> bool{
+        return self.entries.is_empty();
+    }
+
+    pub fn to_hash(&self) -> HashMap<String,String>{
+        let mut h = HashMap::<String,String>::new();
+        self.entries.iter().for_each(|e|{
+            h.insert(e.name.clone(),e.value.to_string());
+        });
+        return h;
+    }
+}
+
+impl fmt::Display for MrdParameters {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for e in self.entries.iter(){
+            match &e.value{
+                MrdValue::Text(t) => writeln!(f,":{} {}, \"{}\"",e.directive,e.name,t)?,
+                _ => writeln!(f,":{} {}, {}",e.directive,e.name,e.value)?
+            }
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test(){
+    let trailer = "\r\n:PPL C:\\ppl\\smisim\\se_3d.PPL\r\n:VAR no_samples, 480\r\n:VAR echo_time, 4.5\r\n\
+        :EDITTEXT LINE_TEXT1 \"cs test\"\r\n:VAR_ARRAY VIEWS_2 1, 2, 3\r\njunk line\r\n";
+    let p = MrdParameters::parse(trailer);
+    assert_eq!(p.get("no_samples"),Some(&MrdValue::Int(480)));
+    assert_eq!(p.get("echo_time"),Some(&MrdValue::Float(4.5)));
+    assert_eq!(p.get("LINE_TEXT1"),Some(&MrdValue::Text("cs test".to_string())));
+    assert_eq!(p.get("VIEWS_2"),Some(&MrdValue::List(vec![MrdValue::Int(1),MrdValue::Int(2),MrdValue::Int(3)])));
+    let reparsed = MrdParameters::parse(&p.to_string());
+    assert_eq!(reparsed.entries,p.entries);
+}
@@ -1,52 +1,255 @@
-use std::fs::File;
+use std::collections::HashMap;
 use std::path::Path;
-use std::io::{BufReader,Read};
+use std::fmt;
+use serde::{Deserialize, Serialize};
 use regex::Regex;
+use crate::mrd::Mrd;
+use crate::cfl;
 
+/*
+Petable
+phase encode stream table. The table lists (kz,ky) coordinate pairs relative to the center of k-space in
+acquisition order. kz places a view along index.0 (dim 2 of the zero-filled k-space) and ky along index.1 (dim 1).
+Values may be separated by any whitespace or line ending. Anything after a # or % is a comment, and lines
+like "size_ky = 480" or "compression: 8" carry metadata. Sizes and compression come from, in order, the file
+metadata, the file name (_CS480_8x_ or _CS480x240_8x_ for ky x kz) and the settings passed in
+*/
+#[derive(Debug,Clone)]
 pub struct Petable {
-    pub file:File,
-    pub size:usize,
+    pub name:String,
+    pub size_ky:usize,
+    pub size_kz:usize,
     pub compression:u32,
+    /* header values that aren't sizes or compression */
+    pub metadata:HashMap<String,String>,
+    coordinates:Vec<(i32,i32)>,
+}
+
+/* sizes and compression for tables whose file doesn't say */
+#[derive(Debug,Deserialize,Serialize,Clone,PartialEq,Default)]
+pub struct PetableSettings{
+    pub size_ky:Option<usize>,
+    pub size_kz:Option<usize>,
+    pub compression:Option<u32>,
 }
 
 impl Petable {
-    pub fn new(path:&str) -> Petable{
-        let pe_table_path = Path::new(path);
-        let file = File::open(pe_table_path).expect("cannot open pe_table");
-        let table_name = pe_table_path.file_name().unwrap().to_str().unwrap();
-        let re = Regex::new(r"_[Cc][Ss]([0-9]{1,})_([0-9]{1,})x_").unwrap();
-        let caps = re.captures(table_name).unwrap();
-        let size:usize = caps.get(1).map_or("", |m| m.as_str()).parse().expect("cannot parse table size");
-        let compression:u32 = caps.get(2).map_or("", |m| m.as_str()).parse().expect("cannot parse table compression");
-        return Petable{file:file,size:size,compression:compression};
-    }
-
-    fn read_values(&self) -> Vec<i32> {
+    pub fn new(path:&str) -> Result<Petable,String>{
+        return Petable::open(path,&PetableSettings::default());
+    }
+
+    pub fn open(path:&str,settings:&PetableSettings) -> Result<Petable,String>{
+        let text = std::fs::read_to_string(path).map_err(|e| format!("cannot read pe table {}: {}",path,e))?;
+        let name = Path::new(path).file_name().and_then(|n| n.to_str()).unwrap_or(path);
+        return Petable::parse(name,&text,settings);
+    }
+
+    /* builds a table from its file name and contents */
+    pub fn parse(name:&str,text:&str,settings:&PetableSettings) -> Result<Petable,String>{
         println!("reading phase encode table ...");
-        let mut file_reader = BufReader::new(&self.file);
-        let mut strbuff = String::new();
-        file_reader.read_to_string(&mut strbuff).expect("oops. Something went wrong reading the pe table");
-        return strbuff.split("\r\n").flat_map(|x| x.parse()).collect();
+        let mut values = Vec::<i32>::new();
+        let mut header = HashMap::<String,String>::new();
+        for (n,line) in text.lines().enumerate(){
+            let line = line.split(['#','%']).next().unwrap().trim();
+            if line.is_empty() {continue}
+            if let Some((key,value)) = line.split_once(['=',':']){
+                header.insert(key.trim().to_ascii_lowercase(),value.trim().to_string());
+                continue;
+            }
+            for token in line.split_whitespace(){
+                values.push(token.parse().map_err(|_| format!("{} line {}: cannot parse {:?} as a phase encode coordinate",name,n+1,token))?);
+            }
+        }
+        if values.len() % 2 != 0 {
+            return Err(format!("{} has an odd number of values ({}). Coordinates come in pairs",name,values.len()));
+        }
+        let coordinates:Vec<(i32,i32)> = values.chunks_exact(2).map(|c| (c[0],c[1])).collect();
+
+        let size = take_number(&mut header,&["size"],name)?;
+        let size_ky = take_number(&mut header,&["size_ky","ky"],name)?.or(size);
+        let size_kz = take_number(&mut header,&["size_kz","kz"],name)?.or(size);
+        let header_compression = take_number(&mut header,&["compression","acceleration"],name)?;
+
+        let re = Regex::new(r"_[Cc][Ss]([0-9]+)(?:x([0-9]+))?_([0-9]+)x_").unwrap();
+        let caps = re.captures(name);
+        let name_value = |i:usize| caps.as_ref().and_then(|c| c.get(i)).and_then(|m| m.as_str().parse::<usize>().ok());
+        let name_ky = name_value(1);
+        let name_kz = name_value(2).or(name_ky);
+
+        let size_ky = size_ky.or(name_ky).or(settings.size_ky)
+            .ok_or(format!("no ky size for {}. Add it to the table header, the file name or the pe table settings",name))?;
+        let size_kz = size_kz.or(name_kz).or(settings.size_kz)
+            .ok_or(format!("no kz size for {}. Add it to the table header, the file name or the pe table settings",name))?;
+        if coordinates.is_empty() {return Err(format!("{} has no phase encode coordinates",name))}
+        // without a stated compression, the grid over the number of views is as good as it gets
+        let compression = header_compression.map(|c| c as u32).or(name_value(3).map(|c| c as u32)).or(settings.compression)
+            .unwrap_or(((size_ky*size_kz) as f64/coordinates.len() as f64).round() as u32);
+
+        return Ok(Petable{
+            name:name.to_string(),
+            size_ky:size_ky,
+            size_kz:size_kz,
+            compression:compression,
+            metadata:header,
+            coordinates:coordinates,
+        });
+    }
+
+    /* phase encodes along index.0 and index.1, the order zero-filled volumes are laid out in */
+    pub fn pe_dims(&self) -> [usize;2]{
+        return [self.size_kz,self.size_ky];
     }
 
     pub fn coordinates(&self) -> Vec<(i32,i32)>{
-        let vals = self.read_values();
-        let n = vals.len()/2;
-        let mut indices:Vec<(i32,i32)> = Vec::with_capacity(n);
-        for i in 0..n {
-            indices.push((vals[2*i],vals[2*i+1]));
-        };
-        return indices;
+        return self.coordinates.clone();
     }
 
     pub fn indices(&self) -> Vec<(usize,usize)> {
-        let coords = self.coordinates();
-        let offset = (self.size/2) as i32;
-        return coords.iter().map(|coord| (
-            (coord.0 + offset) as usize,
-            (coord.1 + offset) as usize
+        let offset = ((self.size_kz/2) as i32,(self.size_ky/2) as i32);
+        return self.coordinates.iter().map(|coord| (
+            (coord.0 + offset.0) as usize,
+            (coord.1 + offset.1) as usize
         )).collect();
     }
+
+    /* entries whose coordinates fall outside the table grid, with their position in the table */
+    pub fn out_of_range(&self) -> Vec<(usize,(i32,i32))>{
+        let dims = self.pe_dims();
+        return self.coordinates.iter().enumerate()
+            .filter(|(_,c)|{
+                let (a,b) = (c.0 + (dims[0]/2) as i32,c.1 + (dims[1]/2) as i32);
+                a < 0 || b < 0 || a as usize >= dims[0] || b as usize >= dims[1]
+            })
+            .map(|(i,c)| (i,*c)).collect();
+    }
+
+    /* times each position of the grid is listed, ky fastest. Entries off the grid aren't counted */
+    pub fn sample_counts(&self) -> Vec<u32>{
+        let dims = self.pe_dims();
+        let mut counts = vec![0u32;dims[0]*dims[1]];
+        for (a,b) in self.indices(){
+            if a < dims[0] && b < dims[1] {counts[b + dims[1]*a] += 1}
+        }
+        return counts;
+    }
+
+    /* side of the largest fully sampled square around the center of k-space */
+    pub fn center_size(&self) -> usize{
+        let dims = self.pe_dims();
+        let counts = self.sample_counts();
+        let mut n = 0;
+        while n < dims[0].min(dims[1]){
+            let side = n + 1;
+            let (a0,b0) = (dims[0]/2 - side/2,dims[1]/2 - side/2);
+            if !(a0..a0+side).all(|a| (b0..b0+side).all(|b| counts[b + dims[1]*a] > 0)) {break}
+            n = side;
+        }
+        return n;
+    }
+
+    /*
+    density_profile
+    fraction of the grid sampled in rings of one sample width around the center. The kz axis is
+    stretched to the ky size, so the rings of non-square tables are ellipses
+    */
+    pub fn density_profile(&self) -> Vec<f32>{
+        let dims = self.pe_dims();
+        let counts = self.sample_counts();
+        let stretch = dims[1] as f64/dims[0] as f64;
+        let ring = |a:usize,b:usize| -> usize{
+            let z = (a as f64 - (dims[0]/2) as f64)*stretch;
+            let y = b as f64 - (dims[1]/2) as f64;
+            return (z*z + y*y).sqrt().floor() as usize;
+        };
+        let n_rings = ring(0,0).max(ring(dims[0]-1,dims[1]-1)) + 1;
+        let mut sampled = vec![0usize;n_rings];
+        let mut total = vec![0usize;n_rings];
+        for a in 0..dims[0]{
+            for b in 0..dims[1]{
+                let r = ring(a,b);
+                total[r] += 1;
+                if counts[b + dims[1]*a] > 0 {sampled[r] += 1}
+            }
+        }
+        return sampled.iter().zip(total.iter()).map(|(s,t)| if *t > 0 {*s as f32/(*t as f32)} else {0.0}).collect();
+    }
+
+    pub fn validate(&self,mrd:&Mrd) -> PetableValidation{
+        let counts = self.sample_counts();
+        let distinct = counts.iter().filter(|c| **c > 0).count();
+        let on_grid = counts.iter().map(|c| *c as usize).sum::<usize>();
+        return PetableValidation{
+            table:self.name.clone(),
+            table_views:self.coordinates.len(),
+            acquired_views:mrd.dim_tuple().0/mrd.channels,
+            out_of_range:self.out_of_range(),
+            duplicates:on_grid - distinct,
+            distinct_positions:distinct,
+            acceleration:if distinct > 0 {counts.len() as f64/distinct as f64} else {f64::INFINITY},
+            center_size:self.center_size(),
+        };
+    }
+
+    /* sample counts as a bart pattern ([1,ky,kz]) to view next to the zero-filled k-space */
+    pub fn write_mask_cfl(&self,path:&Path){
+        let data:Vec<f32> = self.sample_counts().iter().flat_map(|c| [*c as f32,0.0]).collect();
+        cfl::write(path,&data,&[1,self.size_ky,self.size_kz]);
+    }
+
+    pub fn write_density_cfl(&self,path:&Path){
+        let profile = self.density_profile();
+        let data:Vec<f32> = profile.iter().flat_map(|d| [*d,0.0]).collect();
+        cfl::write(path,&data,&[profile.len()]);
+    }
+}
+
+/*
+PetableValidation
+how a stream table fits the data it places. A table is usable when it lists as many views as each
+volume has and every coordinate lands on the grid. Duplicates are reported but allowed, since center
+weighted tables repeat positions on purpose
+*/
+#[derive(Debug,Clone,PartialEq)]
+pub struct PetableValidation{
+    pub table:String,
+    pub table_views:usize,
+    pub acquired_views:usize,
+    /* table position and coordinate of entries off the grid */
+    pub out_of_range:Vec<(usize,(i32,i32))>,
+    pub duplicates:usize,
+    pub distinct_positions:usize,
+    /* grid positions over distinct sampled positions */
+    pub acceleration:f64,
+    pub center_size:usize,
+}
+
+impl PetableValidation{
+    pub fn is_valid(&self) -> bool{
+        return self.table_views == self.acquired_views && self.out_of_range.is_empty();
+    }
+}
+
+impl fmt::Display for PetableValidation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f,"{} is {}",self.table,if self.is_valid() {"valid"} else {"not valid"})?;
+        writeln!(f,"table views: {}, acquired views per volume: {}",self.table_views,self.acquired_views)?;
+        if !self.out_of_range.is_empty(){
+            let shown:Vec<String> = self.out_of_range.iter().take(5).map(|(i,c)| format!("entry {} {:?}",i,c)).collect();
+            writeln!(f,"{} coordinates are off the grid: {}{}",self.out_of_range.len(),shown.join(", "),if self.out_of_range.len() > 5 {" ..."} else {""})?;
+        }
+        writeln!(f,"distinct positions: {}, duplicates: {}",self.distinct_positions,self.duplicates)?;
+        write!(f,"effective acceleration: {:.2}, fully sampled center: {} x {}",self.acceleration,self.center_size,self.center_size)
+    }
+}
+
+/* removes the first of keys from the header and parses it */
+fn take_number(header:&mut HashMap<String,String>,keys:&[&str],name:&str) -> Result<Option<usize>,String>{
+    for key in keys.iter(){
+        if let Some(v) = header.remove(*key){
+            return v.parse().map(Some).map_err(|_| format!("{}: {} = {} isn't a number",name,key,v));
+        }
+    }
+    return Ok(None);
 }
 
 #[test]
@@ -62,6 +265,34 @@ fn test(){
     println!("size = {}",size);
 }
 
+#[test]
+fn test_parse(){
+    let none = PetableSettings::default();
+    // unix line endings and pairs on one line read the same as the scanner's crlf format
+    let crlf = Petable::parse("stream_CS4_2x_pa18_pb54","-2\r\n1\r\n0\r\n-1\r\n",&none).unwrap();
+    let unix = Petable::parse("stream_CS4_2x_pa18_pb54","# made by hand\n-2 1\n0\t-1 % center\n\n",&none).unwrap();
+    assert_eq!(crlf.coordinates(),vec![(-2,1),(0,-1)]);
+    assert_eq!(unix.coordinates(),crlf.coordinates());
+    assert_eq!((unix.size_ky,unix.size_kz,unix.compression),(4,4,2));
+    assert_eq!(unix.indices(),vec![(0,3),(2,1)]);
+
+    // non-square from the header, with the size from the header taking precedence over the name
+    let t = Petable::parse("stream_CS4_2x_","size_ky = 8\nkz: 4\nprotocol = dti\n-2 3\n1 -4\n",&none).unwrap();
+    assert_eq!((t.size_ky,t.size_kz,t.pe_dims()),(8,4,[4,8]));
+    assert_eq!(t.indices(),vec![(0,7),(3,0)]);
+    assert_eq!(t.metadata.get("protocol").unwrap(),"dti");
+    assert_eq!(Petable::parse("stream_CS8x4_2x_","0 0",&none).unwrap().pe_dims(),[4,8]);
+
+    // sizes from settings when the name has none. Compression falls back to the grid over the views
+    assert!(Petable::parse("my_table","0 0\n1 1",&none).is_err());
+    let settings = PetableSettings{size_ky:Some(4),size_kz:Some(2),compression:None};
+    let t = Petable::parse("my_table","0 0\n1 0",&settings).unwrap();
+    assert_eq!((t.size_ky,t.size_kz,t.compression),(4,2,4));
+
+    assert!(Petable::parse("stream_CS4_2x_","0 1 2",&none).unwrap_err().contains("odd number"));
+    assert!(Petable::parse("stream_CS4_2x_","0 1\n2 x",&none).unwrap_err().contains("line 2"));
+}
+
 
 /*
 let re = Regex::new(r"[a-z]+(?:([0-9]+)|([A-Z]+))").unwrap();
@@ -71,4 +302,28 @@ let text1 = caps.get(1).map_or("", |m| m.as_str());
 let text2 = caps.get(2).map_or("", |m| m.as_str());
 assert_eq!(text1, "123");
 assert_eq!(text2, "");
-*/
\ No newline at end of file
+*/
+#[test]
+fn test_validate(){
+    use crate::mrd::MrdWriter;
+    // 3x3 center of a 6x6 grid, one duplicate and one entry off the grid
+    let mut coords:Vec<(i32,i32)> = (-1..2).flat_map(|a| (-1..2).map(move |b| (a,b))).collect();
+    coords.push((2,-3));
+    coords.push((0,0));
+    coords.push((3,0));
+    let text:String = coords.iter().map(|(a,b)| format!("{}\n{}\n",a,b)).collect();
+    let t = Petable::parse("stream_CS6_2x_",&text,&PetableSettings::default()).unwrap();
+    assert_eq!(t.out_of_range(),vec![(11,(3,0))]);
+    assert_eq!(t.center_size(),3);
+    let density = t.density_profile();
+    assert_eq!(density[0],1.0);
+    assert!(density[1] == 1.0 && density[2] < 1.0 && *density.last().unwrap() == 0.0);
+
+    let path = std::env::temp_dir().join("cs_reco_test_validate.mrd");
+    MrdWriter::new([4,12,1,1,1,1]).write_complex_f32(path.to_str().unwrap(),&vec![0.0;4*12*2]);
+    let v = t.validate(&Mrd::new(path.to_str().unwrap()));
+    assert_eq!((v.table_views,v.acquired_views,v.duplicates,v.distinct_positions),(12,12,1,10));
+    assert!((v.acceleration - 3.6).abs() < 1e-9);
+    assert!(!v.is_valid());
+    assert!(v.to_string().contains("entry 11 (3, 0)"));
+}
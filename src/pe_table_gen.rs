@@ -0,0 +1,262 @@
+use serde::{Deserialize, Serialize};
+use std::path::{Path,PathBuf};
+use std::fs::File;
+use std::io::Write;
+use std::str::FromStr;
+
+/*
+PoissonDiscSettings
+variable density poisson-disc stream table. Phase encodes keep a minimum distance from each other that
+grows from the center of k-space to its edge. pa and pb set that distance at the center and at the edge
+relative to each other; the absolute distance is scaled until the table has size_ky*size_kz/acceleration
+views. Everything within center_radius samples of the center is acquired. The same seed always makes the
+same table
+*/
+#[derive(Debug,Deserialize,Serialize,Clone,PartialEq)]
+pub struct PoissonDiscSettings{
+    pub size_ky:usize,
+    pub size_kz:usize,
+    pub acceleration:u32,
+    pub center_radius:f64,
+    pub pa:u32,
+    pub pb:u32,
+    pub seed:u64,
+    pub ordering:ViewOrdering,
+}
+
+/*
+ViewOrdering
+order the views are acquired in
+    Random: shuffled
+    CenterOut: by distance from the center of k-space
+    Segmented: n interleaved segments that each sweep from the center out
+*/
+#[derive(Debug,Deserialize,Serialize,Clone,Copy,PartialEq,Eq)]
+pub enum ViewOrdering{
+    Random,
+    CenterOut,
+    Segmented(usize),
+}
+
+impl FromStr for ViewOrdering{
+    type Err = String;
+    /* random, center-out, segmented (8 segments) or segmented:n */
+    fn from_str(s:&str) -> Result<Self,Self::Err>{
+        let s = s.to_ascii_lowercase();
+        return match s.split_once(':'){
+            Some(("segmented",n)) => n.parse().ok().filter(|n| *n > 0).map(ViewOrdering::Segmented)
+                .ok_or(format!("cannot parse segment count {}",n)),
+            None if s == "segmented" => Ok(ViewOrdering::Segmented(8)),
+            None if s == "random" => Ok(ViewOrdering::Random),
+            None if s == "center-out" || s == "centerout" => Ok(ViewOrdering::CenterOut),
+            _ => Err(format!("unknown view ordering {}. Use random, center-out or segmented:n",s))
+        }
+    }
+}
+
+impl PoissonDiscSettings{
+    pub fn new(size:usize,acceleration:u32,pa:u32,pb:u32) -> PoissonDiscSettings{
+        return PoissonDiscSettings{
+            size_ky:size,
+            size_kz:size,
+            acceleration:acceleration,
+            center_radius:(size as f64/32.0).max(2.0),
+            pa:pa,
+            pb:pb,
+            seed:0,
+            ordering:ViewOrdering::Random,
+        };
+    }
+
+    /* stream_CS480_8x_pa18_pb54, or stream_CS480x240_8x_pa18_pb54 for non-square tables */
+    pub fn table_name(&self) -> String{
+        let size = match self.size_ky == self.size_kz{
+            true => self.size_ky.to_string(),
+            false => format!("{}x{}",self.size_ky,self.size_kz),
+        };
+        return format!("stream_CS{}_{}x_pa{}_pb{}",size,self.acceleration,self.pa,self.pb);
+    }
+
+    /* (kz,ky) coordinates relative to the center of k-space, in acquisition order */
+    pub fn generate(&self) -> Vec<(i32,i32)>{
+        if self.acceleration == 0 || self.size_ky == 0 || self.size_kz == 0 {
+            panic!("table size and acceleration must be at least 1: {:?}",self);
+        }
+        if self.pa == 0 && self.pb == 0 {panic!("pa and pb cannot both be 0")}
+        let n = self.size_ky*self.size_kz;
+        let target = ((n as f64/self.acceleration as f64).round() as usize).max(1);
+        let center:Vec<bool> = (0..n).map(|i| self.radius(i) <= self.center_radius).collect();
+        let n_center = center.iter().filter(|c| **c).count();
+        if n_center > target {
+            panic!("a center radius of {} holds {} views but the table only has {}",self.center_radius,n_center,target);
+        }
+
+        let mut rng = SplitMix64::new(self.seed);
+        let mut candidates:Vec<usize> = (0..n).filter(|i| !center[*i]).collect();
+        rng.shuffle(&mut candidates);
+
+        // fewer views are accepted as the spacing grows. Find the largest spacing that still gives enough.
+        // Past max_scale every candidate is farther than the table diagonal from the others, so nothing changes
+        let diagonal = ((self.size_ky*self.size_ky + self.size_kz*self.size_kz) as f64).sqrt();
+        let min_spacing = candidates.iter().map(|i| self.spacing(*i,1.0)).fold(f64::INFINITY,f64::min);
+        let max_scale = if min_spacing > 0.0 {diagonal/min_spacing} else {diagonal};
+        let mut accepted = match n_center == target{
+            // the fully sampled center already fills the table
+            true => self.sample(&center,&[],0.0),
+            false => {
+                let (mut lo,mut hi) = (0.0,1.0);
+                while hi < max_scale && self.sample(&center,&candidates,hi).len() >= target {
+                    lo = hi;
+                    hi *= 2.0;
+                }
+                for _ in 0..30{
+                    let mid = 0.5*(lo + hi);
+                    if self.sample(&center,&candidates,mid).len() >= target {lo = mid} else {hi = mid}
+                }
+                self.sample(&center,&candidates,lo)
+            }
+        };
+        // drop the extra views at random, never from the center
+        while accepted.len() > target{
+            let i = n_center + rng.below(accepted.len() - n_center);
+            accepted.swap_remove(i);
+        }
+
+        match self.ordering{
+            ViewOrdering::Random => rng.shuffle(&mut accepted),
+            ViewOrdering::CenterOut | ViewOrdering::Segmented(_) => {
+                accepted.sort_by(|a,b| self.radius(*a).partial_cmp(&self.radius(*b)).unwrap().then(a.cmp(b)));
+            }
+        }
+        if let ViewOrdering::Segmented(segments) = self.ordering{
+            accepted = (0..segments).flat_map(|s| accepted.iter().skip(s).step_by(segments).cloned().collect::<Vec<usize>>()).collect();
+        }
+        let (cz,cy) = ((self.size_kz/2) as i32,(self.size_ky/2) as i32);
+        return accepted.iter().map(|i| ((i/self.size_ky) as i32 - cz,(i % self.size_ky) as i32 - cy)).collect();
+    }
+
+    /* writes the table to dir under its table name, one value per line with crlf like the scanner expects */
+    pub fn write_table(&self,dir:&Path) -> PathBuf{
+        let path = dir.join(self.table_name());
+        let table:String = self.generate().iter().map(|(a,b)| format!("{}\r\n{}\r\n",a,b)).collect();
+        let mut f = File::create(&path).expect("cannot create pe table");
+        f.write_all(table.as_bytes()).expect("trouble writing pe table");
+        return path;
+    }
+
+    /* distance from the center of k-space in samples */
+    fn radius(&self,i:usize) -> f64{
+        let z = (i/self.size_ky) as f64 - (self.size_kz/2) as f64;
+        let y = (i % self.size_ky) as f64 - (self.size_ky/2) as f64;
+        return (z*z + y*y).sqrt();
+    }
+
+    /* minimum distance to other views at grid position i for a spacing scale */
+    fn spacing(&self,i:usize,scale:f64) -> f64{
+        let half = 0.5*self.size_ky.max(self.size_kz) as f64;
+        let rho = (self.radius(i)/half).min(1.0);
+        return scale*(self.pa as f64 + (self.pb as f64 - self.pa as f64)*rho);
+    }
+
+    /* dart throwing over the shuffled candidates. The center comes first and is always kept */
+    fn sample(&self,center:&[bool],candidates:&[usize],scale:f64) -> Vec<usize>{
+        let (ny,nz) = (self.size_ky as i64,self.size_kz as i64);
+        let mut taken = center.to_vec();
+        let mut accepted:Vec<usize> = (0..center.len()).filter(|i| center[*i]).collect();
+        for i in candidates.iter(){
+            let r = self.spacing(*i,scale);
+            let w = (r.ceil() as i64).min(ny.max(nz));
+            let (z,y) = ((*i/self.size_ky) as i64,(*i % self.size_ky) as i64);
+            let crowded = (z-w..=z+w).filter(|a| (0..nz).contains(a)).any(|a|{
+                (y-w..=y+w).filter(|b| (0..ny).contains(b)).any(|b|{
+                    taken[(b + ny*a) as usize] && (((a-z)*(a-z) + (b-y)*(b-y)) as f64) < r*r
+                })
+            });
+            if !crowded{
+                taken[*i] = true;
+                accepted.push(*i);
+            }
+        }
+        return accepted;
+    }
+}
+
+/* small seeded generator so tables don't change with the version of a random crate */
+struct SplitMix64{
+    state:u64,
+}
+
+impl SplitMix64{
+    fn new(seed:u64) -> SplitMix64{
+        return SplitMix64{state:seed};
+    }
+
+    fn next(&mut self) -> u64{
+        self.state = self.state.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        return z ^ (z >> 31);
+    }
+
+    /* uniform in 0..n */
+    fn below(&mut self,n:usize) -> usize{
+        return ((self.next() >> 11) as f64/(1u64 << 53) as f64*n as f64) as usize;
+    }
+
+    fn shuffle<T>(&mut self,v:&mut [T]){
+        for i in (1..v.len()).rev(){
+            let j = self.below(i + 1);
+            v.swap(i,j);
+        }
+    }
+}
+
+#[test]
+fn test_poisson_disc(){
+    use crate::pe_table::Petable;
+    let mut settings = PoissonDiscSettings::new(64,4,10,40);
+    settings.seed = 7;
+    let dir = std::env::temp_dir();
+    let path = settings.write_table(&dir);
+    assert!(path.ends_with("stream_CS64_4x_pa10_pb40"));
+
+    // the generated table reads back with the name convention alone
+    let table = Petable::new(path.to_str().unwrap()).unwrap();
+    assert_eq!((table.size_ky,table.size_kz,table.compression),(64,64,4));
+    let coords = table.coordinates();
+    assert_eq!(coords.len(),64*64/4);
+    assert!(table.out_of_range().is_empty());
+    assert_eq!(*table.sample_counts().iter().max().unwrap(),1);
+    assert!(table.center_size() >= 2);
+    let density = table.density_profile();
+    assert!(density[0] == 1.0 && density[5] > density[25]);
+
+    // reproducible from the seed
+    assert_eq!(settings.generate(),coords);
+    settings.seed = 8;
+    assert_ne!(settings.generate(),coords);
+
+    settings.ordering = ViewOrdering::CenterOut;
+    let out = settings.generate();
+    let r = |c:&(i32,i32)| c.0*c.0 + c.1*c.1;
+    assert!(out.windows(2).all(|w| r(&w[0]) <= r(&w[1])));
+    settings.ordering = "segmented:4".parse().unwrap();
+    let segmented = settings.generate();
+    assert_eq!(segmented[0],out[0]);
+    assert_eq!(segmented[segmented.len()/4],out[1]);
+
+    let mut wide = PoissonDiscSettings::new(32,3,10,30);
+    wide.size_kz = 16;
+    assert_eq!(wide.table_name(),"stream_CS32x16_3x_pa10_pb30");
+    assert_eq!(wide.generate().len(),171);
+
+    // a center that fills the whole table
+    let mut full = PoissonDiscSettings::new(8,1,10,40);
+    full.center_radius = 100.0;
+    assert_eq!(full.generate().len(),64);
+    // few views and a tiny spacing still end
+    let mut sparse = PoissonDiscSettings::new(16,64,1,1);
+    sparse.center_radius = 0.0;
+    assert_eq!(sparse.generate().len(),4);
+}
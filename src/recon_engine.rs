@@ -0,0 +1,194 @@
+use serde::{Deserialize, Serialize};
+use std::path::{Path,PathBuf};
+use std::fs::File;
+use std::io::Write;
+use std::process::{Command,Stdio};
+use crate::bart_wrapper::{BartPicsSettings,bart_pics};
+use crate::fft::fft_recon;
+use crate::cs_solver::{CsSettings,cs_recon};
+
+/*
+ReconJob
+everything an engine needs to turn one volume of k-space into an image. Paths to cfls are given
+without extension, as bart expects. shared_dir holds data common to every volume of a run (coil sensitivities)
+*/
+#[derive(Debug,Clone)]
+pub struct ReconJob{
+    pub kspace_cfl:String,
+    pub img_cfl:String,
+    pub shared_dir:PathBuf,
+    pub log_file:PathBuf,
+}
+
+/*
+ReconEngine
+a reconstruction backend. The volume manager only hands it a job, so new backends
+don't touch the state machine
+*/
+pub trait ReconEngine{
+    fn name(&self) -> &str;
+    fn reconstruct(&mut self,job:&ReconJob);
+}
+
+/*
+EngineSettings
+which engine reconstructs the volumes, chosen in the project toml with
+    [engine]
+    kind = "External"
+    command = "my_recon {kspace} {imspace} {log}"
+*/
+#[derive(Debug,Deserialize,Serialize,Clone,PartialEq,Default)]
+#[serde(tag="kind")]
+pub enum EngineSettings{
+    #[default]
+    BartPics,
+    ZeroFilled,
+    External{command:String},
+    /* built-in compressed sensing, no bart needed. Settings go in the same table */
+    Native(CsSettings),
+}
+
+impl EngineSettings{
+    pub fn build(&self,bart_settings:&BartPicsSettings) -> Box<dyn ReconEngine>{
+        return match self{
+            EngineSettings::BartPics => Box::new(BartPicsEngine::new(bart_settings.clone())),
+            EngineSettings::ZeroFilled => Box::new(ZeroFilledEngine),
+            EngineSettings::External{command} => Box::new(ExternalEngine::new(command)),
+            EngineSettings::Native(settings) => Box::new(NativeCsEngine::new(settings.clone())),
+        }
+    }
+
+    /* true if bart has to be available on the cluster for this engine */
+    pub fn uses_bart(&self) -> bool{
+        return matches!(self,EngineSettings::BartPics);
+    }
+}
+
+/* compressed sensing reconstruction with bart pics */
+pub struct BartPicsEngine{
+    settings:BartPicsSettings,
+}
+
+impl BartPicsEngine{
+    pub fn new(settings:BartPicsSettings) -> BartPicsEngine{
+        return BartPicsEngine{settings:settings};
+    }
+}
+
+impl ReconEngine for BartPicsEngine{
+    fn name(&self) -> &str{
+        return "bart";
+    }
+
+    fn reconstruct(&mut self,job:&ReconJob){
+        let shared_sens = job.shared_dir.join("coil_sens");
+        bart_pics(&job.kspace_cfl,&job.img_cfl,shared_sens.to_str().unwrap(),&job.log_file,&mut self.settings);
+    }
+}
+
+/* inverse fft of the zero-filled k-space. Fast, but undersampling artifacts are left in */
+pub struct ZeroFilledEngine;
+
+impl ReconEngine for ZeroFilledEngine{
+    fn name(&self) -> &str{
+        return "zero-filled";
+    }
+
+    fn reconstruct(&mut self,job:&ReconJob){
+        let mut log = File::create(&job.log_file).expect("cannot create engine log file");
+        writeln!(log,"inverse fft of {}",job.kspace_cfl).expect("trouble writing to engine log");
+        fft_recon(Path::new(&job.kspace_cfl),Path::new(&job.img_cfl));
+    }
+}
+
+/* compressed sensing with the solver in cs_solver */
+pub struct NativeCsEngine{
+    settings:CsSettings,
+}
+
+impl NativeCsEngine{
+    pub fn new(settings:CsSettings) -> NativeCsEngine{
+        return NativeCsEngine{settings:settings};
+    }
+}
+
+impl ReconEngine for NativeCsEngine{
+    fn name(&self) -> &str{
+        return "native-cs";
+    }
+
+    fn reconstruct(&mut self,job:&ReconJob){
+        cs_recon(&job.kspace_cfl,&job.img_cfl,&self.settings,&job.log_file).save_next_to(&job.log_file);
+    }
+}
+
+/*
+ExternalEngine
+runs any program from a command template. {kspace}, {imspace} and {log} are replaced by the paths
+of the job. The template is split on whitespace and is not run through a shell
+*/
+pub struct ExternalEngine{
+    template:String,
+}
+
+impl ExternalEngine{
+    pub fn new(template:&str) -> ExternalEngine{
+        return ExternalEngine{template:template.to_string()};
+    }
+
+    pub fn argv(&self,job:&ReconJob) -> Vec<String>{
+        return self.template.split_whitespace().map(|token|{
+            token.replace("{kspace}",&job.kspace_cfl)
+            .replace("{imspace}",&job.img_cfl)
+            .replace("{log}",job.log_file.to_str().unwrap())
+        }).collect();
+    }
+}
+
+impl ReconEngine for ExternalEngine{
+    fn name(&self) -> &str{
+        return "external";
+    }
+
+    fn reconstruct(&mut self,job:&ReconJob){
+        let argv = self.argv(job);
+        if argv.is_empty(){panic!("external engine command is empty")}
+        println!("{}",argv.join(" "));
+        let mut log = File::create(&job.log_file).expect("cannot create engine log file");
+        writeln!(log,"{}",argv.join(" ")).expect("trouble writing to engine log");
+        let stderr = log.try_clone().expect("cannot share engine log file handle");
+        let status = Command::new(&argv[0]).args(&argv[1..])
+            .stdout(Stdio::from(log)).stderr(Stdio::from(stderr))
+            .status().unwrap_or_else(|_| panic!("failed to launch {}",argv[0]));
+        if !status.success(){panic!("{} failed! see {:?}",argv[0],job.log_file);}
+        if !Path::new(&job.img_cfl).with_extension("cfl").exists(){
+            panic!("{} did not write {}.cfl",argv[0],job.img_cfl);
+        }
+    }
+}
+
+#[test]
+fn test_engine_settings(){
+    #[derive(Deserialize,Serialize)]
+    struct Project{engine:EngineSettings}
+    let p = Project{engine:EngineSettings::External{command:"my_recon {kspace} {imspace}".to_string()}};
+    let s = toml::to_string(&p).expect("cannot serialize struct");
+    let r:Project = toml::from_str(&s).expect("cannot deserialize struct");
+    assert_eq!(r.engine,p.engine);
+    let r:Project = toml::from_str("[engine]\nkind = \"ZeroFilled\"\n").expect("cannot deserialize struct");
+    assert_eq!(r.engine,EngineSettings::ZeroFilled);
+    let r:Project = toml::from_str("[engine]\nkind = \"Native\"\nlambda = 0.01\n").expect("cannot deserialize struct");
+    match r.engine{
+        EngineSettings::Native(s) => assert_eq!((s.lambda,s.max_iter),(0.01,CsSettings::default().max_iter)),
+        _ => panic!("expected the native engine")
+    }
+
+    let job = ReconJob{
+        kspace_cfl:"/work/00/kspace".to_string(),
+        img_cfl:"/work/00/imspace".to_string(),
+        shared_dir:PathBuf::from("/work"),
+        log_file:PathBuf::from("/work/00/external.log"),
+    };
+    let engine = ExternalEngine::new("my_recon  -i {kspace} -o {imspace}\t--log={log}");
+    assert_eq!(engine.argv(&job),vec!["my_recon","-i","/work/00/kspace","-o","/work/00/imspace","--log=/work/00/external.log"]);
+}
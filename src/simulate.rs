@@ -0,0 +1,223 @@
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::fs::{File,create_dir_all};
+use std::io::Write;
+use std::time::Instant;
+use regex::Regex;
+use rustfft::num_complex::Complex32;
+use crate::utils;
+use crate::cfl;
+use crate::mrd::Mrd;
+use crate::pe_table::Petable;
+use crate::fft::{fft_recon,to_complex,to_interleaved};
+use crate::config::ProjectSettings;
+use crate::recon_engine::ReconJob;
+use crate::metrics::{ReferenceMetrics,ssim_map};
+
+/*
+Retrospective undersampling. A fully sampled cartesian volume is masked down to the phase encodes of a
+candidate stream table and reconstructed with the project engine. The result is compared with the fft of
+the full k-space so tables (compression, pa/pb) can be judged on real data before they are scanned
+*/
+#[derive(Debug,Clone,Serialize,Deserialize)]
+pub struct SimulationReport{
+    pub table:String,
+    pub compression:u32,
+    /* variable density parameters when the table name carries them (_pa18_pb54) */
+    pub pa:Option<u32>,
+    pub pb:Option<u32>,
+    /* distinct phase encodes kept over all phase encodes */
+    pub sampled_fraction:f64,
+    pub engine:String,
+    pub runtime_s:f64,
+    /* least-squares scale applied to the reconstruction before comparing it with the reference */
+    pub scale:f64,
+    pub metrics:ReferenceMetrics,
+}
+
+impl SimulationReport{
+    pub fn to_file(&self,dest_path:&str){
+        let s = serde_json::to_string_pretty(&self).expect("trouble serializing data struct");
+        utils::write_to_file(dest_path,"json",&s);
+    }
+}
+
+/* pa and pb from a table name like stream_CS480_8x_pa18_pb54 */
+pub fn density_parameters(table_name:&str) -> (Option<u32>,Option<u32>){
+    let param = |key:&str| -> Option<u32>{
+        let re = Regex::new(&format!(r"_{}([0-9]+)",key)).unwrap();
+        return re.captures(table_name).and_then(|c| c.get(1)).and_then(|m| m.as_str().parse().ok());
+    };
+    return (param("pa"),param("pb"));
+}
+
+/*
+undersample
+zeros every phase encode of cartesian k-space ([readout,view,slice,channels]) that the table doesn't acquire.
+Returns the fraction of phase encodes kept
+*/
+pub fn undersample(kspace:&mut [Complex32],dims:[usize;4],pe_table:&Petable) -> f64{
+    let lines = dims[1]*dims[2];
+    let mut keep = vec![false;lines];
+    for (slice,view) in pe_table.indices(){
+        if slice >= dims[2] || view >= dims[1] {
+            panic!("table entry (slice {}, view {}) is outside the {} x {} phase encodes of the data",slice,view,dims[2],dims[1]);
+        }
+        keep[view + dims[1]*slice] = true;
+    }
+    kspace.chunks_exact_mut(dims[0]).enumerate().for_each(|(i,line)|{
+        if !keep[i % lines] {line.iter_mut().for_each(|v| *v = Complex32::new(0.0,0.0))}
+    });
+    return keep.iter().filter(|k| **k).count() as f64/lines as f64;
+}
+
+/*
+simulate
+reconstructs one fully sampled volume of the mrd once per table. The reference image goes to outdir/reference
+and each table gets outdir/<table name>/ with its k-space, image, error maps and report.json.
+simulation_summary.tsv compares all tables
+*/
+pub fn simulate(mrd:&Mrd,vol_idx:usize,tables:&[String],project:&ProjectSettings,outdir:&Path) -> Vec<SimulationReport>{
+    create_dir_all(outdir).expect("cannot make simulation directory");
+    let dims = mrd.cartesian_dims();
+    let spatial = [dims[0],dims[1],dims[2]];
+    let full = to_complex(&mrd.cartesian_volume(vol_idx));
+    let full_cfl = outdir.join("reference_kspace");
+    let reference_cfl = outdir.join("reference");
+    cfl::write(&full_cfl,&to_interleaved(&full),&dims);
+    fft_recon(&full_cfl,&reference_cfl);
+    let reference = cfl::to_magnitude(&reference_cfl);
+
+    let mut reports = Vec::<SimulationReport>::new();
+    for table in tables.iter(){
+        let pe_table = Petable::open(table,&project.pe_table).unwrap_or_else(|e| panic!("{}",e));
+        if pe_table.size_ky != dims[1] || pe_table.size_kz != dims[2] {
+            panic!("{} is for a {} x {} grid but the data has {} x {} phase encodes",table,pe_table.size_ky,pe_table.size_kz,dims[1],dims[2]);
+        }
+        let name = Path::new(table).file_name().unwrap().to_str().unwrap().to_string();
+        println!("simulating {} ...",name);
+        let dir = outdir.join(&name);
+        create_dir_all(&dir).expect("cannot make simulation directory");
+
+        let mut kspace = full.clone();
+        let sampled_fraction = undersample(&mut kspace,dims,&pe_table);
+        let kspace_cfl = dir.join("kspace");
+        cfl::write(&kspace_cfl,&to_interleaved(&kspace),&dims);
+
+        let mut engine = project.recon_engine();
+        let job = ReconJob{
+            kspace_cfl:kspace_cfl.to_str().unwrap().to_string(),
+            img_cfl:dir.join("imspace").to_str().unwrap().to_string(),
+            shared_dir:dir.clone(),
+            log_file:dir.join(format!("{}.log",engine.name())),
+        };
+        let start = Instant::now();
+        engine.reconstruct(&job);
+        let runtime_s = start.elapsed().as_secs_f64();
+
+        let recon = cfl::to_magnitude(Path::new(&job.img_cfl));
+        if recon.len() != reference.len() {
+            panic!("{} has {} voxels but the reference has {}",job.img_cfl,recon.len(),reference.len());
+        }
+        // engines don't agree on the image scale, which isn't what is being judged here
+        let scale = least_squares_scale(&recon,&reference);
+        let recon:Vec<f32> = recon.iter().map(|v| (*v as f64*scale) as f32).collect();
+
+        let error:Vec<f32> = recon.iter().zip(reference.iter()).map(|(a,b)| (a - b).abs()).collect();
+        write_real(&dir.join("error_map"),&error,spatial);
+        write_real(&dir.join("ssim_map"),&ssim_map(&recon,&reference,spatial),spatial);
+
+        let (pa,pb) = density_parameters(&name);
+        let report = SimulationReport{
+            table:name,
+            compression:pe_table.compression,
+            pa:pa,
+            pb:pb,
+            sampled_fraction:sampled_fraction,
+            engine:engine.name().to_string(),
+            runtime_s:runtime_s,
+            scale:scale,
+            metrics:ReferenceMetrics::new(&recon,&reference,spatial),
+        };
+        report.to_file(dir.join("report").to_str().unwrap());
+        println!("{}: nrmse {:.4} psnr {:.2} ssim {:.4}",report.table,report.metrics.nrmse,report.metrics.psnr,report.metrics.ssim);
+        reports.push(report);
+        write_summary(&reports,&outdir.join("simulation_summary.tsv"));
+    }
+    return reports;
+}
+
+/* the a minimizing ||a*x - reference|| */
+fn least_squares_scale(x:&[f32],reference:&[f32]) -> f64{
+    let xr = x.iter().zip(reference.iter()).map(|(a,b)| *a as f64*(*b as f64)).sum::<f64>();
+    let xx = x.iter().map(|a| (*a as f64).powi(2)).sum::<f64>();
+    return if xx > 0.0 {xr/xx} else {1.0};
+}
+
+fn write_real(path:&Path,data:&[f32],dims:[usize;3]){
+    let complex:Vec<Complex32> = data.iter().map(|v| Complex32::new(*v,0.0)).collect();
+    cfl::write(path,&to_interleaved(&complex),&dims);
+}
+
+pub fn write_summary(reports:&[SimulationReport],path:&Path){
+    let mut f = File::create(path).expect("cannot create simulation summary");
+    writeln!(f,"table\tcompression\tpa\tpb\tsampled_fraction\tengine\truntime_s\tnrmse\tpsnr\tssim")
+        .expect("trouble writing simulation summary");
+    let opt = |v:Option<u32>| v.map_or("".to_string(),|v| v.to_string());
+    for r in reports.iter(){
+        writeln!(f,"{}\t{}\t{}\t{}\t{:.4}\t{}\t{:.2}\t{:.5}\t{:.3}\t{:.5}",
+            r.table,r.compression,opt(r.pa),opt(r.pb),r.sampled_fraction,r.engine,r.runtime_s,
+            r.metrics.nrmse,r.metrics.psnr,r.metrics.ssim
+        ).expect("trouble writing simulation summary");
+    }
+}
+
+#[test]
+fn test_simulate(){
+    use crate::mrd::MrdWriter;
+    use crate::bart_wrapper::BartPicsSettings;
+    use crate::recon_engine::EngineSettings;
+    use crate::fft::fft_axes;
+    let dims = [8,8,8];
+    let n:usize = dims.iter().product();
+    // a smooth blob so most of the energy is near the center of k-space
+    let img:Vec<Complex32> = (0..n).map(|i|{
+        let (x,y,z) = ((i % 8) as f32 - 4.0,((i/8) % 8) as f32 - 4.0,(i/64) as f32 - 4.0);
+        Complex32::new((-(x*x + y*y + z*z)/6.0).exp(),0.0)
+    }).collect();
+    let mut kspace = img.clone();
+    fft_axes(&mut kspace,&dims,&[0,1,2],false);
+    let dir = std::env::temp_dir().join("cs_reco_simulate_test");
+    create_dir_all(&dir).expect("cannot make directory");
+    let mrd_path = dir.join("cartesian.mrd");
+    MrdWriter::new([8,8,8,1,1,1]).write_complex_f32(mrd_path.to_str().unwrap(),&to_interleaved(&kspace));
+
+    let write_table = |name:&str,coords:Vec<(i32,i32)>| -> String{
+        let path = dir.join(name);
+        let table:String = coords.iter().map(|(a,b)| format!("{}\r\n{}\r\n",a,b)).collect();
+        File::create(&path).unwrap().write_all(table.as_bytes()).unwrap();
+        return path.to_str().unwrap().to_string();
+    };
+    let all:Vec<(i32,i32)> = (-4..4).flat_map(|a| (-4..4).map(move |b| (a,b))).collect();
+    let center:Vec<(i32,i32)> = all.iter().cloned().filter(|(a,b)| a.abs() <= 1 && b.abs() <= 1).collect();
+    let tables = vec![write_table("stream_CS8_1x_full",all),write_table("stream_CS8_7x_pa10_pb40",center)];
+
+    let project = ProjectSettings{
+        label:"simulate_test".to_string(),
+        project_code:"00.test.00".to_string(),
+        incomplete_mrd:Default::default(),
+        zero_fill:Default::default(),
+        channels:None,
+        recon_settings:BartPicsSettings::default(),
+        engine:EngineSettings::ZeroFilled,
+        slabs:None,
+        pe_table:Default::default(),
+    };
+    let reports = simulate(&Mrd::new(mrd_path.to_str().unwrap()),0,&tables,&project,&dir.join("results"));
+    assert_eq!(reports.len(),2);
+    assert!(reports[0].metrics.nrmse < 1e-4 && reports[0].sampled_fraction == 1.0);
+    assert!(reports[1].metrics.nrmse > reports[0].metrics.nrmse && reports[1].metrics.ssim < 1.0);
+    assert_eq!((reports[1].compression,reports[1].pa,reports[1].pb),(7,Some(10),Some(40)));
+    assert_eq!(reports[1].sampled_fraction,9.0/64.0);
+    assert!(dir.join("results").join("stream_CS8_7x_pa10_pb40").join("error_map.cfl").exists());
+}
@@ -0,0 +1,166 @@
+use serde::{Deserialize, Serialize};
+use std::path::{Path,PathBuf};
+use std::fs::{File,create_dir_all};
+use std::io::Write;
+use std::time::Instant;
+use std::sync::Mutex;
+use rayon::prelude::*;
+use rustfft::num_complex::Complex32;
+use crate::cfl;
+use crate::fft::{fft_axes,readout_slab,to_complex,to_interleaved};
+use crate::bart_wrapper::{BartPicsSettings,copy_weights};
+use crate::recon_engine::{EngineSettings,ReconEngine,ReconJob};
+
+/*
+SlabSettings
+decoupled reconstruction. The readout is fully sampled, so after an inverse fft along it every readout
+position is an independent problem. The volume is cut into slabs of slab_thickness readout positions
+that are reconstructed parallel_jobs at a time with the project engine
+*/
+#[derive(Debug,Deserialize,Serialize,Clone,PartialEq)]
+pub struct SlabSettings{
+    /* readout positions per slab. 1 reconstructs 2D phase encode planes */
+    pub slab_thickness:usize,
+    pub parallel_jobs:usize,
+}
+
+/* wraps the project engine, handing it one slab of k-space at a time */
+pub struct SlabEngine{
+    engine:EngineSettings,
+    bart_settings:BartPicsSettings,
+    settings:SlabSettings,
+    name:String,
+}
+
+impl SlabEngine{
+    pub fn new(engine:EngineSettings,bart_settings:BartPicsSettings,settings:SlabSettings) -> SlabEngine{
+        if settings.slab_thickness == 0 || settings.parallel_jobs == 0 {
+            panic!("slab thickness and parallel jobs must be at least 1: {:?}",settings);
+        }
+        let name = format!("{}-slabs",engine.build(&bart_settings).name());
+        return SlabEngine{engine:engine,bart_settings:bart_settings,settings:settings,name:name};
+    }
+}
+
+impl ReconEngine for SlabEngine{
+    fn name(&self) -> &str{
+        return &self.name;
+    }
+
+    fn reconstruct(&mut self,job:&ReconJob){
+        let start = Instant::now();
+        let mut dims = cfl::get_all_dims(Path::new(&job.kspace_cfl));
+        while dims.len() < 4 {dims.push(1)}
+        let readout = dims[0];
+
+        // hybrid space: image along the readout, k-space along the phase encodes
+        let mut hybrid = to_complex(&cfl::load(Path::new(&job.kspace_cfl)));
+        fft_axes(&mut hybrid,&dims,&[0],true);
+
+        let workdir = Path::new(&job.img_cfl).parent().unwrap().join("slabs");
+        create_dir_all(&workdir).expect("cannot make slab directory");
+        let slabs:Vec<(usize,usize)> = (0..readout).step_by(self.settings.slab_thickness)
+            .map(|x0| (x0,self.settings.slab_thickness.min(readout - x0))).collect();
+
+        let log = Mutex::new(File::create(&job.log_file).expect("cannot create slab log file"));
+        writeln!(log.lock().unwrap(),"{} slabs of {} readout positions from {} {:?}",slabs.len(),self.settings.slab_thickness,job.kspace_cfl,dims)
+            .expect("trouble writing to slab log");
+
+        let pool = rayon::ThreadPoolBuilder::new().num_threads(self.settings.parallel_jobs).build().expect("cannot start thread pool");
+        pool.install(|| slabs.par_iter().enumerate().for_each(|(i,(x0,thickness))|{
+            let label = format!("slab_{:03}",i);
+            // back to k-space along the slab so the engine sees ordinary cartesian data
+            let slab = readout_slab(&hybrid,&dims,*x0,*thickness);
+            let mut slab_dims = dims.clone();
+            slab_dims[0] = *thickness;
+            let kspace = workdir.join(format!("{}_kspace",label));
+            cfl::write(&kspace,&to_interleaved(&slab),&slab_dims);
+            // the sampling pattern is the same for every readout position
+            copy_weights(&job.kspace_cfl,kspace.to_str().unwrap());
+
+            let shared_dir = slab_shared_dir(&job.shared_dir,&label,&slab_dims);
+            create_dir_all(&shared_dir).expect("cannot make slab directory");
+            let slab_job = ReconJob{
+                kspace_cfl:kspace.to_str().unwrap().to_string(),
+                img_cfl:workdir.join(format!("{}_imspace",label)).to_str().unwrap().to_string(),
+                shared_dir:shared_dir,
+                log_file:workdir.join(format!("{}.log",label)),
+            };
+            let slab_start = Instant::now();
+            self.engine.build(&self.bart_settings).reconstruct(&slab_job);
+            writeln!(log.lock().unwrap(),"{} readout {}..{} done in {:.1} s",label,x0,x0+thickness,slab_start.elapsed().as_secs_f32())
+                .expect("trouble writing to slab log");
+        }));
+
+        // reassemble along the readout. The engine decides the remaining dimensions (coils may be kept)
+        let first = workdir.join("slab_000_imspace");
+        let mut img_dims = cfl::get_all_dims(&first);
+        img_dims[0] = readout;
+        let img_lines = img_dims[1..].iter().product::<usize>();
+        let mut img = vec![Complex32::new(0.0,0.0);readout*img_lines];
+        for (i,(x0,thickness)) in slabs.iter().enumerate(){
+            let slab_img = workdir.join(format!("slab_{:03}_imspace",i));
+            let slab_dims = cfl::get_all_dims(&slab_img);
+            if slab_dims[0] != *thickness || slab_dims[1..].iter().product::<usize>() != img_lines {
+                panic!("slab {} image has dimensions {:?}, expected {} x {:?}",i,slab_dims,thickness,&img_dims[1..]);
+            }
+            let slab = to_complex(&cfl::load(&slab_img));
+            img.par_chunks_mut(readout).zip(slab.par_chunks(*thickness)).for_each(|(line,s)|{
+                line[*x0..x0+thickness].copy_from_slice(s);
+            });
+        }
+        cfl::write(Path::new(&job.img_cfl),&to_interleaved(&img),&img_dims);
+        writeln!(log.lock().unwrap(),"reassembled {} {:?} in {:.1} s",job.img_cfl,img_dims,start.elapsed().as_secs_f32())
+            .expect("trouble writing to slab log");
+    }
+}
+
+/*
+coil sensitivities of a slab are shared between volumes under its position and full dims, so a map made
+for a different slab thickness (or a thin slab with its singleton readout) is never picked up
+*/
+fn slab_shared_dir(shared_dir:&Path,label:&str,slab_dims:&[usize]) -> PathBuf{
+    let dims:Vec<String> = slab_dims.iter().map(|d| d.to_string()).collect();
+    return shared_dir.join("slabs").join(format!("{}_{}",label,dims.join("x")));
+}
+
+#[test]
+fn test_slab_recon(){
+    let dims = [10,4,6,2];
+    let n:usize = dims.iter().product();
+    let kspace:Vec<Complex32> = (0..n).map(|i| Complex32::new((i % 11) as f32 - 5.0,(i % 4) as f32)).collect();
+    let dir = std::env::temp_dir().join("cs_reco_slab_test");
+    create_dir_all(&dir).expect("cannot make directory");
+    let kspace_cfl = dir.join("kspace");
+    cfl::write(&kspace_cfl,&to_interleaved(&kspace),&dims);
+
+    let job = ReconJob{
+        kspace_cfl:kspace_cfl.to_str().unwrap().to_string(),
+        img_cfl:dir.join("imspace").to_str().unwrap().to_string(),
+        shared_dir:dir.clone(),
+        log_file:dir.join("slabs.log"),
+    };
+    // 3 doesn't divide the readout, so the last slab is thinner
+    let settings = SlabSettings{slab_thickness:3,parallel_jobs:2};
+    let mut engine = SlabEngine::new(EngineSettings::ZeroFilled,BartPicsSettings::default(),settings);
+    assert_eq!(engine.name(),"zero-filled-slabs");
+    engine.reconstruct(&job);
+
+    let mut expected = kspace.clone();
+    fft_axes(&mut expected,&dims,&[0,1,2],true);
+    let img = to_complex(&cfl::load(Path::new(&job.img_cfl)));
+    assert_eq!(cfl::get_all_dims(Path::new(&job.img_cfl)),vec![10,4,6,2,1]);
+    img.iter().zip(expected.iter()).for_each(|(a,b)| assert!((a-b).norm() < 1e-4));
+
+    // 2D planes: every slab keeps its singleton readout and gets a sens map of the same layout
+    let settings = SlabSettings{slab_thickness:1,parallel_jobs:2};
+    let mut engine = SlabEngine::new(EngineSettings::ZeroFilled,BartPicsSettings::default(),settings);
+    engine.reconstruct(&job);
+    let img = to_complex(&cfl::load(Path::new(&job.img_cfl)));
+    img.iter().zip(expected.iter()).for_each(|(a,b)| assert!((a-b).norm() < 1e-4));
+    let slab_kspace = dir.join("slabs").join("slab_009_kspace");
+    assert_eq!(cfl::get_dims_trimmed(&slab_kspace),vec![1,4,6,2]);
+    let sens = BartPicsSettings::default().unit_sens_command(slab_kspace.to_str().unwrap(),"sens");
+    assert_eq!(sens.argv(),vec!["bart","ones","4","1","4","6","2","sens"]);
+    assert_eq!(slab_shared_dir(&dir,"slab_009",&[1,4,6,2]),dir.join("slabs").join("slab_009_1x4x6x2"));
+}
@@ -0,0 +1,194 @@
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::fs::{File,create_dir_all};
+use std::io::Write;
+use std::time::Instant;
+use crate::utils;
+use crate::cfl;
+use crate::bart_wrapper::{BartPicsSettings,Convergence,bart_pics};
+use crate::metrics::ImageMetrics;
+
+/*
+SweepSettings
+grid of pics settings to try on one volume. Every combination of algorithm, lambda and iteration
+count is reconstructed. Stacked regularizers of the project are left out so lambda takes effect
+*/
+#[derive(Debug,Deserialize,Serialize,Clone,PartialEq)]
+pub struct SweepSettings{
+    pub lambdas:Vec<f32>,
+    pub max_iters:Vec<u32>,
+    pub algorithms:Vec<String>,
+    /* write a montage of the central slice of every result */
+    #[serde(default)]
+    pub montage:bool,
+}
+
+#[derive(Debug,Clone)]
+pub struct SweepPoint{
+    pub label:String,
+    pub algorithm:String,
+    pub lambda:f32,
+    pub max_iter:u32,
+    pub settings:BartPicsSettings,
+}
+
+#[derive(Debug,Clone)]
+pub struct SweepResult{
+    pub point:SweepPoint,
+    pub runtime_s:f64,
+    pub convergence:Convergence,
+    pub metrics:ImageMetrics,
+}
+
+impl SweepSettings{
+    pub fn open(path:&str) -> SweepSettings{
+        return match utils::read_to_string(path,"toml"){
+            Ok(str) => toml::from_str(&str).expect("Cannot deserialize file. Is it the correct format?"),
+            Err(_) => {
+                println!("Sweep settings not found, creating a default template.");
+                SweepSettings::new_template(path)
+            }
+        }
+    }
+
+    pub fn new_template(path:&str) -> SweepSettings{
+        let sweep = SweepSettings{
+            lambdas:vec![0.001,0.005,0.01,0.05],
+            max_iters:vec![20,50],
+            algorithms:vec!["l1".to_string()],
+            montage:true,
+        };
+        utils::write_to_file(path,"toml",&toml::to_string(&sweep).expect("cannot serialize struct"));
+        return sweep;
+    }
+
+    pub fn combinations(&self,base:&BartPicsSettings) -> Vec<SweepPoint>{
+        let mut points = Vec::<SweepPoint>::new();
+        for algorithm in self.algorithms.iter(){
+            for lambda in self.lambdas.iter(){
+                for max_iter in self.max_iters.iter(){
+                    let mut settings = base.clone();
+                    settings.regularizers.clear();
+                    settings.set_regularization(algorithm,*lambda).set_max_iter(*max_iter);
+                    points.push(SweepPoint{
+                        label:format!("{}_lambda{}_iter{}",algorithm,lambda,max_iter),
+                        algorithm:algorithm.clone(),
+                        lambda:*lambda,
+                        max_iter:*max_iter,
+                        settings:settings,
+                    });
+                }
+            }
+        }
+        return points;
+    }
+}
+
+/*
+run_sweep
+reconstructs kspace_cfl once per combination into outdir/<label>/imspace. Coil sensitivities are
+made once and shared. sweep_summary.tsv (and sweep_montage.pgm) are written to outdir
+*/
+pub fn run_sweep(kspace_cfl:&str,outdir:&Path,base:&BartPicsSettings,sweep:&SweepSettings) -> Vec<SweepResult>{
+    create_dir_all(outdir).expect("cannot make sweep directory");
+    let shared_sens = outdir.join("coil_sens");
+    let points = sweep.combinations(base);
+    let mut results = Vec::<SweepResult>::new();
+    for (i,point) in points.into_iter().enumerate(){
+        println!("sweep {}: {}",i,point.label);
+        let dir = outdir.join(&point.label);
+        create_dir_all(&dir).expect("cannot make sweep directory");
+        let img = dir.join("imspace");
+        let log = dir.join("bart.log");
+        let mut settings = point.settings.clone();
+        let start = Instant::now();
+        let convergence = bart_pics(kspace_cfl,img.to_str().unwrap(),shared_sens.to_str().unwrap(),&log,&mut settings);
+        let runtime_s = start.elapsed().as_secs_f64();
+        results.push(SweepResult{
+            point:point,
+            runtime_s:runtime_s,
+            convergence:convergence,
+            metrics:volume_metrics(&img),
+        });
+        // keep the summary current so a partial sweep is still useful
+        write_summary(&results,&outdir.join("sweep_summary.tsv"));
+    }
+    if sweep.montage{
+        let slices:Vec<(Vec<f32>,[usize;2])> = results.iter()
+            .map(|r| cfl::central_slice(&outdir.join(&r.point.label).join("imspace"))).collect();
+        write_montage(&slices,&outdir.join("sweep_montage.pgm"));
+    }
+    return results;
+}
+
+fn volume_metrics(img_cfl:&Path) -> ImageMetrics{
+    let dims = cfl::get_dims(img_cfl);
+    if dims.len() < 3 {panic!("{:?} isn't a volume. dims: {:?}",img_cfl,dims)}
+    return ImageMetrics::new(&cfl::to_magnitude(img_cfl),[dims[0],dims[1],dims[2]]);
+}
+
+/* one row per reconstruction, in the order they are tiled in the montage */
+pub fn write_summary(results:&[SweepResult],path:&Path){
+    let mut f = File::create(path).expect("cannot create sweep summary");
+    writeln!(f,"label\talgorithm\tlambda\tmax_iter\truntime_s\titerations\tfinal_objective\tsnr\tsharpness\tmean\tmax")
+        .expect("trouble writing sweep summary");
+    for r in results.iter(){
+        let objective = r.convergence.iterations.last().and_then(|i| i.objective).map_or("".to_string(),|o| o.to_string());
+        writeln!(f,"{}\t{}\t{}\t{}\t{:.2}\t{}\t{}\t{:.3}\t{:.5}\t{}\t{}",
+            r.point.label,r.point.algorithm,r.point.lambda,r.point.max_iter,r.runtime_s,
+            r.convergence.iterations.len(),objective,r.metrics.snr,r.metrics.sharpness,r.metrics.mean,r.metrics.max
+        ).expect("trouble writing sweep summary");
+    }
+}
+
+/*
+write_montage
+tiles slices row by row into an 8-bit pgm. Each tile is windowed to its own 99.5th percentile so
+differences in scaling between settings don't hide structure
+*/
+pub fn write_montage(slices:&[(Vec<f32>,[usize;2])],path:&Path){
+    if slices.is_empty() {return}
+    let [w,h] = slices[0].1;
+    if slices.iter().any(|s| s.1 != [w,h]) {panic!("montage slices must all be the same size")}
+    let cols = (slices.len() as f64).sqrt().ceil() as usize;
+    let rows = slices.len().div_ceil(cols);
+    let mut pixels = vec![0u8;cols*w*rows*h];
+    for (i,(slice,_)) in slices.iter().enumerate(){
+        let mut sorted = slice.clone();
+        sorted.sort_by(|a,b| a.partial_cmp(b).unwrap());
+        let white = sorted[((sorted.len() - 1) as f64*0.995) as usize].max(f32::MIN_POSITIVE);
+        let (row,col) = (i/cols,i % cols);
+        for y in 0..h{
+            for x in 0..w{
+                let v = (slice[x + w*y]/white*255.0).clamp(0.0,255.0) as u8;
+                pixels[(row*h + y)*cols*w + col*w + x] = v;
+            }
+        }
+    }
+    let mut f = File::create(path).expect("cannot create montage");
+    write!(f,"P5\n{} {}\n255\n",cols*w,rows*h).expect("trouble writing montage");
+    f.write_all(&pixels).expect("trouble writing montage");
+}
+
+#[test]
+fn test_sweep(){
+    let sweep = SweepSettings{
+        lambdas:vec![0.001,0.01],
+        max_iters:vec![10,30,50],
+        algorithms:vec!["l1".to_string(),"l2".to_string()],
+        montage:true,
+    };
+    let points = sweep.combinations(&BartPicsSettings::default());
+    assert_eq!(points.len(),12);
+    assert_eq!(points[1].label,"l1_lambda0.001_iter30");
+    let args = points[11].settings.pics_command("k","s","i").to_string();
+    assert!(args.contains("-l2 -r0.01") && args.contains("-i50"));
+
+    let slices = vec![(vec![1.0;6],[3,2]);5];
+    let path = std::env::temp_dir().join("cs_reco_test_montage.pgm");
+    write_montage(&slices,&path);
+    let bytes = std::fs::read(&path).unwrap();
+    let header = "P5\n9 4\n255\n";
+    assert!(bytes.starts_with(header.as_bytes()));
+    assert_eq!(bytes.len(),header.len() + 9*4);
+}
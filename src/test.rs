@@ -2,7 +2,7 @@ use crate::{resource::*, utils};
 use crate::volume_index::VolumeIndex;
 use std::collections::{HashMap, HashSet};
 use serde::{Deserialize, Serialize};
-use std::fs::{File,create_dir_all};
+use std::fs::{File,create_dir_all,remove_file};
 use std::io::{Read,Write};
 use std::path::{Path, PathBuf};
 use whoami;
@@ -12,6 +12,8 @@ use crate::volume_manager::{VmState,VolumeManager,launch_volume_manager,launch_v
 use crate::slurm::{self,BatchScript, JobState};
 use std::process::Command;
 use crate::config::Recon;
+use crate::mrd::{IncompleteMrdPolicy,MrdValidation,MRD_SETTLE};
+use crate::pe_table::{Petable,PetableValidation};
 
 /*
     headfile=mrs_meta_data(mrd);
@@ -41,13 +43,20 @@ pub fn main_test_cluster(){
 
     let mut recon = Recon::new("grumpy","testrunno0001",vpath,"5xfad","dummyspec");
 
+    recon.project.validate().unwrap_or_else(|e| panic!("{}",e));
+    /* make sure bart runs and supports the project settings before any slurm jobs are submitted */
+    if recon.project.engine.uses_bart(){
+        let bart_version = recon.project.recon_settings.probe_bart().unwrap_or_else(|e| panic!("{}",e));
+        println!("using bart {}",bart_version);
+        recon.bart_version = Some(bart_version.to_string());
+        recon.save();
+    }
+
     let cwd = recon.engine_work_dir.join(format!("{}.work",&recon.run_number));
     if !cwd.exists(){ create_dir_all(&cwd).expect("unable to create specified working directory")}
     
     let volman_jobs_file = cwd.join("volume-manager-jobs").with_extension("toml");
 
-    let mrd_vol_offset = 0;
-    
     //bart_settings.to_file(bart_settings_file);
 
     let raw_base_path = Path::new(vpath).parent().unwrap();
@@ -58,8 +67,6 @@ pub fn main_test_cluster(){
     let ready_mrds = VolumeIndex::read_ready(&local_vpath);
     let all_mrds = VolumeIndex::read_all(&local_vpath);
 
-    recon.n_volumes = Some(all_mrds.len());
-
     let mut r = ResourceList::open(local_raw_path.to_str().unwrap());
     r.set_host(&recon.scanner.host());
     ready_mrds.iter().for_each(|(mrd,_)| {
@@ -71,6 +78,17 @@ pub fn main_test_cluster(){
     });
     r.start_transfer();
 
+    /*
+        Each mrd may hold several volumes (repetitions or echoes). Every contained volume gets its own
+        volume manager, numbered the same way as the volume index
+    */
+    if recon.label_width.is_none(){
+        recon.label_width = VolumeIndex::label_width(&all_mrds,&local_raw_path,recon.expected_volumes);
+    }
+    let volumes = VolumeIndex::expand(&all_mrds,&local_raw_path,recon.label_width);
+    recon.n_volumes = Some(volumes.len());
+    recon.save();
+
     /*
         This builds a hashmap of volume managers and their slurm
         job ids that will updated and saved every time this runs
@@ -89,17 +107,58 @@ pub fn main_test_cluster(){
     println!("{:?}",vol_man_jobs);
 
     /*
-        We are assuming a one-to-one mapping a mrd file to a volume manager
-        in "volume_index" mode. If a mrd file is available, and a volume manager
-        hasn't already been launched, a new volume manager will be instantiated
+        If a volume's mrd file is available, and a volume manager hasn't already been launched,
+        a new volume manager will be instantiated
     */
-    all_mrds.iter().for_each(|(index,mrd)| {
-        let voldir = cwd.join(index);
-        if !voldir.exists(){create_dir_all(&voldir).expect("issue creating directory");}
-        if !VolumeManager::exists(voldir.to_str().unwrap()) && mrd.is_some(){
+    let mut validations = HashMap::<PathBuf,MrdValidation>::new();
+    let mut stable = HashMap::<PathBuf,bool>::new();
+    let index_tables = VolumeIndex::read_tables(&local_vpath);
+    let mut tables = HashMap::<PathBuf,Petable>::new();
+    let mut table_checks = HashMap::<(PathBuf,PathBuf),PetableValidation>::new();
+    volumes.iter().for_each(|vol| {
+        let voldir = cwd.join(&vol.label);
+        /* volumes skipped on an earlier run stay skipped, unless their mrd has grown since */
+        let marker = skipped_marker(&voldir);
+        if marker.exists(){
+            if !skip_is_stale(&marker,vol.mrd.as_deref()) {return}
+            println!("{:?} has grown since volume {} was skipped. Checking it again",vol.mrd.as_ref().unwrap(),vol.label);
+            remove_file(&marker).expect("cannot remove skipped marker");
+        }
+        if !VolumeManager::exists(voldir.to_str().unwrap()) && vol.mrd.is_some(){
+            let mrd_path = vol.mrd.clone().unwrap();
+            /* truncated or in-progress mrd files are skipped or waited on depending on the project settings */
+            let validation = validations.entry(mrd_path.clone()).or_insert_with(||
+                MrdValidation::new(mrd_path.to_str().unwrap(),MRD_SETTLE)
+            );
+            if !validation.volume_is_ready(vol.vol_offset,recon.project.incomplete_mrd){
+                match recon.project.incomplete_mrd {
+                    /* a stalled transfer looks finished for a moment, so a skip needs the size to hold over several checks */
+                    IncompleteMrdPolicy::Skip if !validation.in_progress && *stable.entry(mrd_path.clone()).or_insert_with(||
+                        (1..SKIP_STABLE_CHECKS).all(|_| !MrdValidation::new(mrd_path.to_str().unwrap(),MRD_SETTLE).in_progress)
+                    ) => {
+                        println!("skipping volume {}: {}",vol.label,validation);
+                        create_dir_all(&voldir).expect("issue creating directory");
+                        utils::write_to_file(marker.to_str().unwrap(),"txt",&format!("{}\n{}",validation.actual_bytes,validation));
+                    }
+                    _ => println!("waiting on volume {}: {}",vol.label,validation),
+                }
+                return;
+            }
+            /* volumes may use their own table. One that doesn't fit the data would only fail once the job runs */
+            let table = recon.pe_tables.resolve(vol,&index_tables,Path::new(ptab));
+            let petab = tables.entry(table.clone()).or_insert_with(||
+                Petable::open(table.to_str().unwrap(),&recon.project.pe_table).unwrap_or_else(|e| panic!("{}",e))
+            );
+            let table_check = table_checks.entry((mrd_path.clone(),table.clone())).or_insert_with(||
+                petab.validate(&recon.project.open_mrd(mrd_path.to_str().unwrap()))
+            );
+            if !table_check.is_valid(){
+                println!("skipping volume {}: {}",vol.label,table_check);
+                return;
+            }
             println!("vol man doesn't exist and mrd is available... submitting new job");
-            let mrd_path = local_raw_path.join(mrd.clone().unwrap());
-            let job_id = launch_volume_manager_job(voldir.to_str().unwrap(),mrd_path.to_str().unwrap(),&ptab,mrd_vol_offset,&recon.path());
+            if !voldir.exists(){create_dir_all(&voldir).expect("issue creating directory");}
+            let job_id = launch_volume_manager_job(voldir.to_str().unwrap(),mrd_path.to_str().unwrap(),table.to_str().unwrap(),vol.vol_offset,&recon.path());
             vol_man_jobs.insert(voldir.clone(),job_id);
         }
     });
@@ -135,9 +194,7 @@ pub fn main_test_cluster(){
         stop condition for rescheduling.
     */
 
-    //let mc = all_mrds.clone();
-    let mut m:Vec<&String> = all_mrds.keys().collect();
-    m.sort();
+    let m:Vec<&String> = volumes.iter().map(|vol| &vol.label).collect();
     //println!("sorted idx: {:?}",m);
 
     let mut state_str = String::new();
@@ -148,6 +205,7 @@ pub fn main_test_cluster(){
         if s == VmState::Done {n_completed += 1};
         let slurm_state = job_states.get(&voldir);
         match slurm_state {
+            None if skipped_marker(&voldir).exists() => state_str.push_str(&format!("{} : skipped (incomplete mrd)\t\n",index)),
             Some(state) => state_str.push_str(&format!("{} : slurm job : {:?}; volume-manager : {:?}\t\n",index,state,&s)),
             None => state_str.push_str(&format!("{} : slurm job : not submitted; volume-manager : {:?}\t\n",index,&s))
         }
@@ -170,7 +228,38 @@ pub fn main_test_cluster(){
     
 }
 
+/* consecutive stable size checks of an incomplete mrd before its volumes are skipped */
+const SKIP_STABLE_CHECKS:usize = 3;
+
+/*
+written to the volume directory when a volume is left out of the reconstruction. The first line is the
+size of the mrd when it was skipped
+*/
+fn skipped_marker(voldir:&Path) -> PathBuf{
+    return voldir.join("skipped").with_extension("txt");
+}
+
+/* true if the mrd is now larger than when the volume was skipped */
+fn skip_is_stale(marker:&Path,mrd:Option<&Path>) -> bool{
+    let mrd = match mrd {Some(mrd) => mrd, None => return false};
+    let s = utils::read_to_string(marker.to_str().unwrap(),"txt").expect("cannot read skipped marker");
+    let skipped_size = s.lines().next().and_then(|l| l.trim().parse::<u64>().ok()).unwrap_or(0);
+    return std::fs::metadata(mrd).map(|m| m.len() > skipped_size).unwrap_or(false);
+}
+
 #[test]
 fn test(){
     let r = Recon::new("grumpy","N60400","some/data","5xfad","220304");
-}
\ No newline at end of file
+}
+#[test]
+fn test_skip_marker(){
+    let dir = std::env::temp_dir().join("cs_reco_test_skip");
+    create_dir_all(&dir).unwrap();
+    let mrd = dir.join("growing.mrd");
+    std::fs::write(&mrd,vec![0u8;1000]).unwrap();
+    let marker = skipped_marker(&dir);
+    utils::write_to_file(marker.to_str().unwrap(),"txt","1000\nmrd is truncated");
+    assert!(!skip_is_stale(&marker,Some(&mrd)));
+    std::fs::write(&mrd,vec![0u8;2000]).unwrap();
+    assert!(skip_is_stale(&marker,Some(&mrd)));
+}
@@ -1,20 +1,34 @@
 use std::io::Read;
 use std::path::{Path,PathBuf};
-use std::collections::HashMap;
+use std::collections::{BTreeMap,HashMap};
 use std::fs::File;
+use serde::{Deserialize, Serialize};
 use crate::resource::{Resource,Host};
+use std::time::Duration;
+use crate::mrd::MrdValidation;
 
 pub struct VolumeIndex{
 
 }
 
+/*
+    One volume to reconstruct. An mrd holding several volumes (repetitions, echoes) produces one
+    entry per contained volume, each with its own label and offset into the mrd
+*/
+#[derive(Debug,Clone,PartialEq)]
+pub struct VolumeEntry{
+    pub label:String,
+    pub mrd:Option<PathBuf>,
+    pub vol_offset:usize,
+}
+
 impl VolumeIndex{
     pub fn read_ready(path:&str) -> HashMap<String,String>{
         let mut h = HashMap::<String,String>::new();
         let str = VolumeIndex::read_to_string(path);
         str.lines().for_each(|line| {
             let strvec:Vec<&str> = line.split_whitespace().collect();
-            if strvec.len() == 2 {
+            if strvec.len() >= 2 {
                 h.insert(strvec[1].to_string(),strvec[0].to_string());
             }
         }
@@ -29,13 +43,100 @@ impl VolumeIndex{
             let strvec:Vec<&str> = line.split_whitespace().collect();
             match strvec.len() {
                 1 => {h.insert(strvec[0].to_string(),None)},
-                2 => {h.insert(strvec[0].to_string(),Some(strvec[1].to_string()))},
+                2 | 3 => {h.insert(strvec[0].to_string(),Some(strvec[1].to_string()))},
                 _ => panic!("has the volume index been corrupted?")
             };
         });
         return h;
     }
 
+    /*
+        read_tables
+        An optional third column names the phase encode table of every volume in that row's mrd.
+        Returns the tables by mrd file name
+    */
+    pub fn read_tables(path:&str) -> HashMap<String,String>{
+        let mut h = HashMap::<String,String>::new();
+        let str = VolumeIndex::read_to_string(path);
+        str.lines().for_each(|line| {
+            let strvec:Vec<&str> = line.split_whitespace().collect();
+            if strvec.len() == 3 {
+                h.insert(strvec[1].to_string(),strvec[2].to_string());
+            }
+        });
+        return h;
+    }
+
+    /*
+        label_width
+        Zero padding of the volume labels, fixed once for the whole run so a label never changes as mrd
+        files arrive. It comes from the expected volume count when it is configured, otherwise from the
+        first available mrd assuming every mrd in the index holds as many volumes. None until one of them
+        is known
+    */
+    pub fn label_width(all_mrds:&HashMap<String,Option<String>>,local_raw_path:&Path,expected_volumes:Option<usize>) -> Option<usize>{
+        let mut indices:Vec<&String> = all_mrds.keys().collect();
+        indices.sort();
+        let n_total = match expected_volumes{
+            Some(n) => n,
+            None => {
+                let per_mrd = indices.iter().find_map(|index|{
+                    let path = local_raw_path.join(all_mrds.get(*index).unwrap().as_ref()?);
+                    return VolumeIndex::available_volumes(&path);
+                })?;
+                per_mrd*indices.len()
+            }
+        };
+        let index_width = indices.iter().map(|i| i.len()).max().unwrap_or(1);
+        return Some(index_width.max((n_total.max(1) - 1).to_string().len()));
+    }
+
+    /*
+        expand
+        Maps every volume contained in the indexed mrd files to a volume label. Labels are numbered
+        consecutively in index order and padded to width (see label_width; the index width when it isn't
+        known yet). An mrd that isn't available locally, or is still too short to have a header, is
+        assumed to hold one volume, and every mrd after it is held back (mrd is None) until it arrives
+        so that labels never change once a volume manager is launched.
+    */
+    pub fn expand(all_mrds:&HashMap<String,Option<String>>,local_raw_path:&Path,width:Option<usize>) -> Vec<VolumeEntry>{
+        let mut indices:Vec<&String> = all_mrds.keys().collect();
+        indices.sort();
+        let mut counts = Vec::<(Option<PathBuf>,usize)>::new();
+        let mut blocked = false;
+        indices.iter().for_each(|index|{
+            let mrd_path = all_mrds.get(*index).unwrap().as_ref().map(|mrd| local_raw_path.join(mrd));
+            let n_vols = mrd_path.as_ref().filter(|_| !blocked).and_then(|path| VolumeIndex::available_volumes(path));
+            match n_vols {
+                Some(n_vols) => counts.push((mrd_path,n_vols)),
+                None => {
+                    blocked = true;
+                    counts.push((None,1));
+                }
+            }
+        });
+        let n_total:usize = counts.iter().map(|(_,n)| n).sum();
+        let width = width.unwrap_or(indices.iter().map(|i| i.len()).max().unwrap_or(1));
+        let mut entries = Vec::<VolumeEntry>::with_capacity(n_total);
+        counts.into_iter().for_each(|(mrd,n_vols)|{
+            for vol_offset in 0..n_vols{
+                entries.push(VolumeEntry{
+                    label:format!("{:0width$}",entries.len(),width=width),
+                    mrd:mrd.clone(),
+                    vol_offset:vol_offset
+                });
+            }
+        });
+        return entries;
+    }
+
+    /* volumes in the mrd header, or None while the file is missing or shorter than its header */
+    fn available_volumes(path:&Path) -> Option<usize>{
+        if !path.exists() {return None}
+        let n_vols = MrdValidation::new(path.to_str().unwrap(),Duration::ZERO).total_volumes;
+        return if n_vols > 0 {Some(n_vols)} else {None};
+    }
+
     fn read_to_string(path:&str) -> String{
         let fpath = Path::new(path);
         let mut f = File::open(fpath).expect("cannot open file");
@@ -54,6 +155,46 @@ impl VolumeIndex{
 }
 
 
+/*
+    PetableMap
+    Run-level assignment of phase encode tables to volumes, for protocols that change the undersampling
+    pattern between volumes. Keys are a volume label ("07") or an inclusive range of labels ("00-06").
+    A volume takes, in order, its own label, the narrowest range holding it, the table in the volume
+    index for its mrd, the map default, and finally the run table. Relative table paths are taken from
+    the directory of the run table
+*/
+#[derive(Debug,Clone,PartialEq,Serialize,Deserialize,Default)]
+pub struct PetableMap{
+    #[serde(default)]
+    pub default:Option<String>,
+    #[serde(default)]
+    pub volumes:BTreeMap<String,String>,
+}
+
+impl PetableMap{
+    /* the table the map itself gives a volume label, if any */
+    pub fn table_for(&self,label:&str) -> Option<&String>{
+        if let Some(table) = self.volumes.get(label) {return Some(table)}
+        let n:usize = label.parse().ok()?;
+        return self.volumes.iter().filter_map(|(key,table)|{
+            let (a,b) = key.split_once('-')?;
+            let (a,b):(usize,usize) = (a.trim().parse().ok()?,b.trim().parse().ok()?);
+            if (a..=b).contains(&n) {Some((b - a,table))} else {None}
+        }).min_by_key(|(width,_)| *width).map(|(_,table)| table);
+    }
+
+    pub fn resolve(&self,volume:&VolumeEntry,index_tables:&HashMap<String,String>,run_table:&Path) -> PathBuf{
+        let from_index = volume.mrd.as_ref()
+            .and_then(|mrd| mrd.file_name())
+            .and_then(|name| index_tables.get(name.to_str().unwrap()));
+        let table = self.table_for(&volume.label).or(from_index).or(self.default.as_ref());
+        return match table{
+            Some(t) => run_table.parent().unwrap_or(Path::new("")).join(t),
+            None => run_table.to_owned(),
+        };
+    }
+}
+
 #[test]
 fn test(){
     let vpath = "/Users/Wyatt/local_recon/volume_index.txt";
@@ -61,4 +202,63 @@ fn test(){
     let h1 = VolumeIndex::read_all(vpath);
     println!("{:?}",h);
     println!("{:?}",h1);
-}
\ No newline at end of file
+}
+#[test]
+fn test_expand(){
+    use crate::mrd::MrdWriter;
+    let raw = std::env::temp_dir().join("cs_reco_test_expand");
+    std::fs::create_dir_all(&raw).unwrap();
+    // the first mrd holds 3 repetitions, the second holds one volume
+    let w = MrdWriter::new([2,1,1,3,1,1]);
+    w.write_complex_f32(raw.join("a.mrd").to_str().unwrap(),&vec![0.0;12]);
+    let w = MrdWriter::new([2,1,1,1,1,1]);
+    w.write_complex_f32(raw.join("b.mrd").to_str().unwrap(),&vec![0.0;4]);
+    let mut h = HashMap::<String,Option<String>>::new();
+    h.insert("00".to_string(),Some("a.mrd".to_string()));
+    h.insert("01".to_string(),Some("b.mrd".to_string()));
+    h.insert("02".to_string(),None);
+    let width = VolumeIndex::label_width(&h,&raw,None);
+    assert_eq!(width,Some(2));
+    let e = VolumeIndex::expand(&h,&raw,width);
+    let labels:Vec<&str> = e.iter().map(|v| v.label.as_str()).collect();
+    assert_eq!(labels,vec!["00","01","02","03","04"]);
+    assert_eq!(e[2].vol_offset,2);
+    assert_eq!(e[3].mrd,Some(raw.join("b.mrd")));
+    assert_eq!(e[4].mrd,None);
+
+    // single digit index. 4 mrds of 3 volumes each need two digits from the start
+    let mut h = HashMap::<String,Option<String>>::new();
+    h.insert("0".to_string(),Some("a.mrd".to_string()));
+    h.insert("1".to_string(),Some("c.mrd".to_string()));
+    h.insert("2".to_string(),None);
+    h.insert("3".to_string(),None);
+    // c.mrd is still being copied and doesn't have a full header yet
+    std::fs::write(raw.join("c.mrd"),vec![0u8;100]).unwrap();
+    assert_eq!(VolumeIndex::label_width(&h,&raw,None),Some(2));
+    assert_eq!(VolumeIndex::label_width(&h,&raw,Some(8)),Some(1));
+    let e = VolumeIndex::expand(&h,&raw,Some(2));
+    assert_eq!(e.len(),6);
+    assert_eq!((e[0].label.as_str(),e[3].mrd.clone()),("00",None));
+}
+
+#[test]
+fn test_table_assignment(){
+    use std::io::Write;
+    let vpath = std::env::temp_dir().join("cs_reco_test_volume_index.txt");
+    File::create(&vpath).unwrap().write_all(b"00 a.mrd table_a\n01 b.mrd\n02\n").unwrap();
+    let vpath = vpath.to_str().unwrap();
+    assert_eq!(VolumeIndex::read_all(vpath).get("00").unwrap(),&Some("a.mrd".to_string()));
+    assert_eq!(VolumeIndex::read_ready(vpath).len(),2);
+    let index_tables = VolumeIndex::read_tables(vpath);
+    assert_eq!(index_tables.get("a.mrd").unwrap(),"table_a");
+
+    let map:PetableMap = toml::from_str("default = \"table_d\"\n[volumes]\n\"00-03\" = \"table_r\"\n\"02-02\" = \"table_n\"\n\"05\" = \"/abs/table_x\"\n").unwrap();
+    let entry = |label:&str,mrd:&str| VolumeEntry{label:label.to_string(),mrd:Some(PathBuf::from("/raw").join(mrd)),vol_offset:0};
+    let run_table = Path::new("/tables/stream_CS256_8x_pa18_pb54");
+    assert_eq!(map.resolve(&entry("01","a.mrd"),&index_tables,run_table),PathBuf::from("/tables/table_r"));
+    assert_eq!(map.resolve(&entry("02","a.mrd"),&index_tables,run_table),PathBuf::from("/tables/table_n"));
+    assert_eq!(map.resolve(&entry("05","a.mrd"),&index_tables,run_table),PathBuf::from("/abs/table_x"));
+    assert_eq!(map.resolve(&entry("04","a.mrd"),&index_tables,run_table),PathBuf::from("/tables/table_a"));
+    assert_eq!(map.resolve(&entry("06","b.mrd"),&index_tables,run_table),PathBuf::from("/tables/table_d"));
+    assert_eq!(PetableMap::default().resolve(&entry("06","b.mrd"),&index_tables,run_table),run_table.to_owned());
+}
@@ -1,6 +1,6 @@
-use crate::mrd::Mrd;
+use crate::mrd::{MrdValidation,MRD_SETTLE};
 use crate::pe_table::Petable;
-use crate::bart_wrapper::{BartPicsSettings,bart_pics};
+use crate::recon_engine::ReconJob;
 use serde::{Deserialize, Serialize};
 use std::path::{Path,PathBuf};
 use std::fs::{File,create_dir_all,remove_file};
@@ -104,30 +104,42 @@ impl VolumeManager {
     pub fn advance(workdir:&str) -> VolumeManager{
         use VmState::*;
         let mut vm = VolumeManager::open(workdir);
-        let mut r = Recon::open(&vm.reco_settings.to_str().unwrap()).unwrap();
+        let r = Recon::open(&vm.reco_settings.to_str().unwrap()).unwrap();
         match vm.state {
             Idle => {
                 vm.advance_state();
             }
             PreProcessing => {
-                //if Path::new(&vm.mrd).exists(){
-                    let mut mrd = Mrd::new(&vm.mrd);
-                    mrd.load_volume(vm.mrd_vol_offset as u16);
-                    let petab = Petable::new(&vm.phase_table);
+                let validation = MrdValidation::new(&vm.mrd,MRD_SETTLE);
+                let complete = validation.volume_is_complete(vm.mrd_vol_offset);
+                if !complete {println!("{}",validation)}
+                if validation.volume_is_ready(vm.mrd_vol_offset,r.project.incomplete_mrd){
+                    let mrd = r.project.open_mrd(&vm.mrd);
+                    let petab = Petable::open(&vm.phase_table,&r.project.pe_table).unwrap_or_else(|e| panic!("{}",e));
                     let mrd_name = Path::new(&vm.mrd).with_extension("");
                     let mrd_name = mrd_name.file_name().unwrap().to_str().unwrap();
                     let kspace = Path::new(&workdir).join(&format!("{}_kspace",mrd_name)).with_extension("");
-                    mrd.write_zero_filled_cfl(kspace.to_str().unwrap(), &petab);
+                    mrd.write_zero_filled_volume_cfl(vm.mrd_vol_offset,kspace.to_str().unwrap(),&petab,!complete);
                     vm.kspace = Some(kspace.to_str().unwrap().to_string());
                     vm.advance_state();
-                //}
+                }else{
+                    println!("volume {} of {} isn't available yet. Will try again later",vm.mrd_vol_offset,vm.mrd);
+                }
             },
             Reconstructing => {
                 let kspace = vm.kspace.clone().unwrap();
                 let mrd_name = Path::new(&vm.mrd).with_extension("");
                 let mrd_name = mrd_name.file_name().unwrap().to_str().unwrap();
                 let imspace = Path::new(&workdir).join(&format!("{}_imspace",mrd_name)).with_extension("");
-                bart_pics(&kspace,imspace.to_str().unwrap(),&mut r.project.recon_settings);
+                let mut engine = r.project.recon_engine();
+                let job = ReconJob{
+                    kspace_cfl:kspace,
+                    img_cfl:imspace.to_str().unwrap().to_string(),
+                    // data shared by every volume of the run (coil sensitivities) goes one level up
+                    shared_dir:Path::new(&workdir).parent().unwrap().to_owned(),
+                    log_file:Path::new(&workdir).join(format!("{}.log",engine.name())),
+                };
+                engine.reconstruct(&job);
                 vm.imspace = Some(imspace.to_str().unwrap().to_string());
                 vm.advance_state();
             }
@@ -182,7 +194,14 @@ impl VolumeManager {
 
                 let meta_path = base.join(&meta_name);
                 println!("meta path: {:?}",meta_path);
-                let mut hf = Headfile::from_mrd_meta(&meta_path);
+                let mut hf = match meta_path.exists(){
+                    true => Headfile::from_mrd_meta(&meta_path),
+                    false => {
+                        println!("meta file not found. Using parameters from the mrd trailer instead");
+                        let mrd = r.project.open_mrd(&vm.mrd);
+                        Headfile::from_mrd_parameters(&mrd.parameters())
+                    }
+                };
                 let headfile = outdir.join(&format!("{}.headfile",&imgname));
                 
             
@@ -220,6 +239,10 @@ impl VolumeManager {
                 hf.append_field("civm_image_code", &r.scanner.image_code);
                 hf.append_field("civm_image_source_tag", &r.scanner.image_source_tag);
                 hf.append_field("engine_work_directory",&r.engine_work_dir.to_str().unwrap());
+                // tables can change between volumes, so each headfile names its own
+                let table = Path::new(&vm.phase_table);
+                hf.append_field("cs_table",table.file_name().unwrap().to_str().unwrap());
+                hf.append_field("cs_table_path",&vm.phase_table);
                 hf.write_headfile(&headfile);
 
             }